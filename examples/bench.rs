@@ -0,0 +1,29 @@
+use std::env;
+use std::time::Instant;
+use um::um::UM;
+use um::umio::MemoryIo;
+
+/*
+ * Times a program with its output kept in memory so the terminal doesn't
+ * skew the numbers:
+ *   cargo run --release --example bench -- tests/sandmark.umz
+ */
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        eprintln!("Usage: {} <program.um>", args[0]);
+        return;
+    }
+
+    let mut machine = UM::with_io(MemoryIo::default());
+    if let Err(e) = machine.init_program(&args[1]) {
+        eprintln!("{}: {}", args[1], e);
+        return;
+    }
+
+    let start = Instant::now();
+    let result = machine.run();
+    let elapsed = start.elapsed();
+
+    println!("{:?} in {:.3}s", result, elapsed.as_secs_f64());
+}
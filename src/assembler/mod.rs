@@ -1,6 +1,95 @@
+use crate::loader::{self, Image, ImageFormat, LineInfo, Symbol};
 use crate::um::{UmOp, UmOperations, UmWord};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::Range;
+
+/* largest value a load value instruction can hold */
+const MAX_VALUE: u32 = 0x1FFFFFF;
+/* registers are r0 through r7 */
+const NUM_REGISTERS: u32 = 8;
+
+/* anything from one of these to the end of the line is ignored */
+const COMMENT_MARKERS: [&str; 3] = ["//", "#", ";"];
+
+/* label names that would read as part of an instruction */
+const RESERVED: [&str; 8] = ["if", "m", "map", "unmap", "out", "in", "halt", "goto"];
+
+/* A problem in a ums source file, reported with enough context to point at it */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AsmError {
+    pub path: String,
+    /* 1-based, or 0 when the problem is with the file as a whole */
+    pub line: usize,
+    /* byte offsets into `source` */
+    pub columns: Range<usize>,
+    /* the offending line as written */
+    pub source: String,
+    pub reason: String,
+}
+
+/* What went wrong on a line, before it is tied to a file */
+#[derive(PartialEq, Eq, Debug)]
+struct LineError {
+    reason: String,
+    span: Range<usize>,
+}
+
+type LineResult<T> = Result<T, LineError>;
+
+impl AsmError {
+    fn new(path: &str, line: usize, source: &str, error: LineError) -> Self {
+        Self {
+            path: path.to_string(),
+            line,
+            columns: error.span,
+            source: source.to_string(),
+            reason: error.reason,
+        }
+    }
+}
+
+/*
+ * rustc-style report, e.g.
+ * error: unknown binop `%`
+ * --> foo.ums:3:9
+ *   |
+ * 3 | r1 := r2 % r3
+ *   |          ^
+ */
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "error: {}", self.reason)?;
+        if self.line == 0 {
+            return write!(f, " --> {}", self.path);
+        }
+
+        let before = &self.source[..self.columns.start];
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter,
+            self.path,
+            self.line,
+            before.chars().count() + 1
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source)?;
+
+        /* keep tabs so the carets line up with the source */
+        let padding: String = before
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self.source[self.columns.clone()].chars().count().max(1);
+        write!(f, "{} | {}{}", gutter, padding, "^".repeat(width))
+    }
+}
+
+impl std::error::Error for AsmError {}
 
 pub struct UMAssembler {}
 
@@ -10,7 +99,7 @@ impl UMAssembler {
         instruction |= opcode << 28;
         instruction |= reg_a << 6;
         instruction |= reg_b << 3;
-        instruction |= reg_c << 0; /* no shift */
+        instruction |= reg_c; /* no shift */
 
         instruction
     }
@@ -24,13 +113,58 @@ impl UMAssembler {
         instruction
     }
 
-    fn parse_instruction(line: &str) -> Option<u32> {
-        let line = line.trim().replace(" ", "");
+    /* error spans are byte offsets into `line` as given */
+    fn parse_instruction(line: &str, labels: &HashMap<String, u32>) -> LineResult<u32> {
+        /*
+         * whitespace outside quotes is dropped before parsing, so remember
+         * where each remaining byte came from to point errors at the
+         * original text
+         */
+        let trimmed = line.trim();
+        let start = trimmed.as_ptr() as usize - line.as_ptr() as usize;
+        let mut stripped = String::with_capacity(trimmed.len());
+        let mut origin = Vec::with_capacity(trimmed.len() + 1);
+        for (i, c, quoted) in UMAssembler::quoted(trimmed) {
+            if quoted || !c.is_whitespace() {
+                stripped.push(c);
+                origin.extend((0..c.len_utf8()).map(|j| start + i + j));
+            }
+        }
+        origin.push(start + trimmed.len());
+
+        /*
+         * a label has to be the whole right hand side as written, so that
+         * `r2 := map r1` is never read as a label named `mapr1`
+         */
+        let labels = match trimmed.split_once(":=") {
+            Some((_, right)) if UMAssembler::is_label(right.trim()) => Some(labels),
+            _ => None,
+        };
+
+        UMAssembler::parse_stripped(&stripped, labels).map_err(|e| LineError {
+            span: if e.span.is_empty() {
+                origin[e.span.start]..origin[e.span.start]
+            } else {
+                origin[e.span.start]..origin[e.span.end - 1] + 1
+            },
+            reason: e.reason,
+        })
+    }
+
+    /*
+     * expects a line with no spaces, error spans index into it. `labels` is
+     * only given when the right hand side is a single name as written.
+     */
+    fn parse_stripped(line: &str, labels: Option<&HashMap<String, u32>>) -> LineResult<u32> {
+        if line.is_empty() {
+            return Err(UMAssembler::error(line, line, "expected an instruction"));
+        }
+
         if let Some((left, right)) = line.split_once(":=") {
             if left.starts_with("m[") {
-                let (segment_id, offset) = UMAssembler::parse_memory(left)?;
-                let reg = UMAssembler::parse_reg(right)?;
-                return Some(UMAssembler::build_three_reg_instruction(
+                let (segment_id, offset) = UMAssembler::parse_memory(line, left)?;
+                let reg = UMAssembler::expect_reg(line, right)?;
+                return Ok(UMAssembler::build_three_reg_instruction(
                     UmOp::SSTORE as u32,
                     segment_id,
                     offset,
@@ -39,8 +173,35 @@ impl UMAssembler {
             }
 
             // left *must* be a register
-            let lreg =
-                UMAssembler::parse_reg(left).expect("Invalid left register in binop expression");
+            let lreg = UMAssembler::expect_reg(line, left)?;
+
+            // label, checked first so names like `nandy` aren't read as a binop
+            if let Some(labels) = labels {
+                let address = match labels.get(right) {
+                    Some(&address) => address,
+                    None => {
+                        return Err(UMAssembler::error(
+                            line,
+                            right,
+                            &format!("undefined label `{}`", right),
+                        ))
+                    }
+                };
+                return Ok(UMAssembler::build_load_value_instruction(
+                    UmOp::LV as u32,
+                    lreg,
+                    UMAssembler::expect_value(line, right, address)?,
+                ));
+            }
+
+            // load value, checked before binops so that '+' is a character
+            if let Some(value) = UMAssembler::parse_literal(line, right)? {
+                return Ok(UMAssembler::build_load_value_instruction(
+                    UmOp::LV as u32,
+                    lreg,
+                    UMAssembler::expect_value(line, right, value)?,
+                ));
+            }
 
             // binop
             for (opcode_expr, opcode) in [
@@ -50,9 +211,9 @@ impl UMAssembler {
                 ("nand", UmOp::NAND),
             ] {
                 if let Some((bin1, bin2)) = right.split_once(opcode_expr) {
-                    let rb = UMAssembler::parse_reg(bin1)?;
-                    let rc = UMAssembler::parse_reg(bin2)?;
-                    return Some(UMAssembler::build_three_reg_instruction(
+                    let rb = UMAssembler::expect_reg(line, bin1)?;
+                    let rc = UMAssembler::expect_reg(line, bin2)?;
+                    return Ok(UMAssembler::build_three_reg_instruction(
                         opcode as u32,
                         lreg,
                         rb,
@@ -62,8 +223,9 @@ impl UMAssembler {
             }
 
             // segmented load
-            if let Some((segment_id, offset)) = UMAssembler::parse_memory(right) {
-                return Some(UMAssembler::build_three_reg_instruction(
+            if right.starts_with("m[") {
+                let (segment_id, offset) = UMAssembler::parse_memory(line, right)?;
+                return Ok(UMAssembler::build_three_reg_instruction(
                     UmOp::SLOAD as u32,
                     lreg,
                     segment_id,
@@ -72,8 +234,8 @@ impl UMAssembler {
             }
 
             // cmov
-            if let Some((src, test)) = UMAssembler::parse_condition(right) {
-                return Some(UMAssembler::build_three_reg_instruction(
+            if let Some((src, test)) = UMAssembler::parse_condition(line, right)? {
+                return Ok(UMAssembler::build_three_reg_instruction(
                     UmOp::CMOV as u32,
                     lreg,
                     src,
@@ -82,22 +244,15 @@ impl UMAssembler {
             }
 
             // map
-            if let Some(size) = UMAssembler::parse_size(right) {
-                return Some(UMAssembler::build_three_reg_instruction(
+            if let Some(size) = UMAssembler::parse_size(line, right)? {
+                return Ok(UMAssembler::build_three_reg_instruction(
                     UmOp::MAP as u32,
                     0,
                     lreg,
                     size,
                 ));
             }
-            // load value
-            if let Some(value) = UMAssembler::parse_value(right) {
-                return Some(UMAssembler::build_load_value_instruction(
-                    UmOp::LV as u32,
-                    lreg,
-                    value,
-                ));
-            }
+            return Err(UMAssembler::explain_operand(line, right));
         }
         // unop
         for (unop_expr, opcode) in [
@@ -106,8 +261,8 @@ impl UMAssembler {
             ("in", UmOperations::IN),
         ] {
             if let Some(reg) = line.strip_prefix(unop_expr) {
-                let rc = UMAssembler::parse_reg(reg)?;
-                return Some(UMAssembler::build_three_reg_instruction(
+                let rc = UMAssembler::expect_reg(line, reg)?;
+                return Ok(UMAssembler::build_three_reg_instruction(
                     opcode as u32,
                     0,
                     0,
@@ -117,18 +272,24 @@ impl UMAssembler {
         }
 
         if let Some(inner_expr) = line.strip_prefix("goto") {
-            if let Some((segment_id, offset)) = UMAssembler::parse_memory(inner_expr) {
-                return Some(UMAssembler::build_three_reg_instruction(
-                    UmOp::LOADP as u32,
-                    0,
-                    segment_id,
-                    offset,
-                ));
-            }
+            let (segment_id, offset) = UMAssembler::parse_memory(line, inner_expr)?;
+            return Ok(UMAssembler::build_three_reg_instruction(
+                UmOp::LOADP as u32,
+                0,
+                segment_id,
+                offset,
+            ));
         }
 
-        if let Some(_) = line.strip_prefix("halt") {
-            return Some(UMAssembler::build_three_reg_instruction(
+        if let Some(rest) = line.strip_prefix("halt") {
+            if !rest.is_empty() {
+                return Err(UMAssembler::error(
+                    line,
+                    rest,
+                    &format!("unexpected `{}` after halt", rest),
+                ));
+            }
+            return Ok(UMAssembler::build_three_reg_instruction(
                 UmOp::HALT as u32,
                 0,
                 0,
@@ -136,7 +297,87 @@ impl UMAssembler {
             ));
         }
 
-        None
+        Err(UMAssembler::error(line, line, "unrecognized instruction"))
+    }
+
+    /* `part` must be a slice of `line` */
+    fn error(line: &str, part: &str, reason: &str) -> LineError {
+        let start = part.as_ptr() as usize - line.as_ptr() as usize;
+        LineError {
+            reason: reason.to_string(),
+            span: start..start + part.len(),
+        }
+    }
+
+    /* works out why the right hand side of `rA := ...` made no sense */
+    fn explain_operand(line: &str, right: &str) -> LineError {
+        if right.is_empty() {
+            return UMAssembler::error(line, right, "expected a register, value or label");
+        }
+        if right.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+            return UMAssembler::error(line, right, &format!("invalid value `{}`", right));
+        }
+        if UMAssembler::is_label(right) {
+            return UMAssembler::error(line, right, &format!("undefined label `{}`", right));
+        }
+
+        /* `rB ?? rC` */
+        if let Some(rest) = right.strip_prefix('r') {
+            let op_start = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            if op_start > 0 {
+                if let Some(op_end) = rest.rfind('r') {
+                    let digits = &rest[op_end + 1..];
+                    if op_end > op_start
+                        && !digits.is_empty()
+                        && digits.chars().all(|c| c.is_ascii_digit())
+                    {
+                        let op = &rest[op_start..op_end];
+                        return UMAssembler::error(line, op, &format!("unknown binop `{}`", op));
+                    }
+                }
+            }
+        }
+
+        UMAssembler::error(
+            line,
+            right,
+            &format!("expected a register, value or label, found `{}`", right),
+        )
+    }
+
+    fn expect_reg(line: &str, part: &str) -> LineResult<u32> {
+        if let Some(reg) = UMAssembler::parse_reg(part) {
+            return Ok(reg);
+        }
+
+        let reason = match UMAssembler::parse_reg_number(part) {
+            Some(reg) => format!("register r{} out of range 0..{}", reg, NUM_REGISTERS - 1),
+            None if part.is_empty() => String::from("expected a register"),
+            None => format!("expected a register, found `{}`", part),
+        };
+        Err(UMAssembler::error(line, part, &reason))
+    }
+
+    fn expect_value(line: &str, part: &str, value: u32) -> LineResult<u32> {
+        if value > MAX_VALUE {
+            /* a literal whose complement fits never gets here, see `parse_wide_load` */
+            let reason = if part.starts_with('-') {
+                format!("negative value {} is below -{}", part, MAX_VALUE + 1)
+            } else if UMAssembler::is_label(part) {
+                format!("value {} exceeds 25 bits", part)
+            } else {
+                format!(
+                    "value {} exceeds 25 bits, and only values from 0x{:X} up can be \
+                     loaded as two instructions",
+                    part, !MAX_VALUE
+                )
+            };
+            return Err(UMAssembler::error(line, part, &reason));
+        }
+
+        Ok(value)
     }
 
     fn parse_value(line: &str) -> Option<u32> {
@@ -144,95 +385,604 @@ impl UMAssembler {
             u32::from_str_radix(hex, 16).ok()
         } else if let Some(bin) = line.strip_prefix("0b") {
             u32::from_str_radix(bin, 2).ok()
+        } else if let Some(digits) = line.strip_prefix('-') {
+            /* two's complement, so -1 is 0xFFFFFFFF */
+            digits
+                .parse::<u32>()
+                .ok()
+                .filter(|&n| n <= 1 << 31 && digits.starts_with(|c: char| c.is_ascii_digit()))
+                .map(u32::wrapping_neg)
         } else {
             line.parse::<u32>().ok()
         }
     }
-    fn parse_size(line: &str) -> Option<u32> {
-        if let Some(inner_expr) = line.strip_prefix("map") {
-            if let Some(reg) = UMAssembler::parse_reg(inner_expr) {
-                return Some(reg);
+
+    /*
+     * `rA := k` where k does not fit in 25 bits but its complement does,
+     * such as -1 or 0xFFFFFFFF, has no single LV, so it becomes
+     * `rA := !k; rA := rA nand rA`. Anything else, including errors, is
+     * left to `parse_instruction`.
+     */
+    fn parse_wide_load(line: &str) -> Option<[UmWord; 2]> {
+        let (left, right) = line.split_once(":=")?;
+        if !right
+            .trim_start()
+            .starts_with(|c: char| c.is_ascii_digit() || c == '-')
+        {
+            return None;
+        }
+        let left: String = left.split_whitespace().collect();
+        let right: String = right.split_whitespace().collect();
+        let reg = UMAssembler::parse_reg(&left)?;
+        let value = UMAssembler::parse_value(&right)?;
+        (value > MAX_VALUE && !value <= MAX_VALUE).then(|| {
+            [
+                UMAssembler::build_load_value_instruction(UmOp::LV as u32, reg, !value),
+                UMAssembler::build_three_reg_instruction(UmOp::NAND as u32, reg, reg, reg),
+            ]
+        })
+    }
+
+    fn parse_literal(line: &str, part: &str) -> LineResult<Option<u32>> {
+        let inner = match part.strip_prefix('\'') {
+            Some(inner) => inner,
+            None => return Ok(UMAssembler::parse_value(part)),
+        };
+        let chars = match inner.strip_suffix('\'') {
+            Some(chars) => UMAssembler::unescape(line, chars, '\'')?,
+            None => {
+                return Err(UMAssembler::error(
+                    line,
+                    part,
+                    "unterminated character literal",
+                ))
             }
+        };
+        match chars[..] {
+            [c] => Ok(Some(c)),
+            _ => Err(UMAssembler::error(
+                line,
+                part,
+                &format!("`{}` is not a single character", part),
+            )),
+        }
+    }
+    fn parse_size(line: &str, part: &str) -> LineResult<Option<u32>> {
+        match part.strip_prefix("map") {
+            Some(inner_expr) => Ok(Some(UMAssembler::expect_reg(line, inner_expr)?)),
+            None => Ok(None),
         }
+    }
+    fn parse_condition(line: &str, part: &str) -> LineResult<Option<(u32, u32)>> {
+        match part.split_once("if") {
+            Some((r1, r2)) => Ok(Some((
+                UMAssembler::expect_reg(line, r1)?,
+                UMAssembler::expect_reg(line, r2)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /* returns the segment id and the offset */
+    fn parse_memory(line: &str, part: &str) -> LineResult<(u32, u32)> {
+        let inner_expr = match part.strip_prefix("m[").and_then(|p| p.strip_suffix(']')) {
+            Some(inner_expr) => inner_expr,
+            None => {
+                return Err(UMAssembler::error(
+                    line,
+                    part,
+                    &format!("expected m[<reg>][<reg>], found `{}`", part),
+                ))
+            }
+        };
+        match inner_expr.split_once("][") {
+            Some((r1, r2)) => Ok((
+                UMAssembler::expect_reg(line, r1)?,
+                UMAssembler::expect_reg(line, r2)?,
+            )),
+            None => Err(UMAssembler::error(
+                line,
+                part,
+                &format!("expected m[<reg>][<reg>], found `{}`", part),
+            )),
+        }
+    }
+    /* a label is an identifier that isn't a register or a keyword */
+    fn is_label(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+            _ => return false,
+        }
+
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && UMAssembler::parse_reg_number(name).is_none()
+            && !RESERVED.contains(&name)
+    }
 
-        None
+    /*
+     * each character with whether it belongs to a string or character
+     * literal, quotes included
+     */
+    fn quoted(line: &str) -> impl Iterator<Item = (usize, char, bool)> + '_ {
+        let (mut quote, mut escaped) = (None, false);
+        line.char_indices().map(move |(i, c)| {
+            let inside = quote.is_some();
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == '\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == '"' || c == '\'' => quote = Some(c),
+                None => {}
+            }
+            (i, c, inside || quote.is_some())
+        })
     }
-    fn parse_condition(line: &str) -> Option<(u32, u32)> {
-        if let Some((r1, r2)) = line.split_once("if") {
-            return Some((UMAssembler::parse_reg(r1)?, UMAssembler::parse_reg(r2)?));
+
+    /* everything before the first comment marker outside a literal */
+    fn strip_comment(line: &str) -> &str {
+        for (i, _, quoted) in UMAssembler::quoted(line) {
+            let commented = COMMENT_MARKERS
+                .iter()
+                .any(|marker| line[i..].starts_with(marker));
+            if !quoted && commented {
+                return &line[..i];
+            }
         }
 
-        None
+        line
     }
 
-    /* returns the segment id and the offset iff succeeded */
-    fn parse_memory(line: &str) -> Option<(u32, u32)> {
-        if line.starts_with("m[") {
-            let inner_expr = &line[2..line.len() - 1];
-            if let Some((r1, r2)) = inner_expr.split_once("][") {
-                return Some((UMAssembler::parse_reg(r1)?, UMAssembler::parse_reg(r2)?));
+    /* splits directive arguments on the commas outside literals */
+    fn split_args(args: &str) -> Vec<&str> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+        for (i, c, quoted) in UMAssembler::quoted(args) {
+            if c == ',' && !quoted {
+                parts.push(args[start..i].trim());
+                start = i + 1;
             }
         }
+        parts.push(args[start..].trim());
+
+        parts
+    }
+
+    /*
+     * how many words a line assembles to, without building them. A
+     * directive that doesn't parse counts as empty here and is reported by
+     * the second pass.
+     */
+    fn size_of(line: &str, address: u32) -> u32 {
+        if line.is_empty() {
+            return 0;
+        } else if !line.starts_with('.') {
+            return match UMAssembler::parse_wide_load(line) {
+                Some(words) => words.len() as u32,
+                None => 1,
+            };
+        }
+        let (name, args) = UMAssembler::split_directive(line);
+        let size = match name {
+            ".word" => Ok(1),
+            ".words" => Ok(UMAssembler::split_args(args).len() as u32),
+            ".string" => UMAssembler::string_body(line, args).and_then(|inner| {
+                let mut count = 0;
+                UMAssembler::unescape_with(line, inner, '"', |_| count += 1)?;
+                Ok(count)
+            }),
+            ".zero" => UMAssembler::parse_count(line, args),
+            ".align" => UMAssembler::padding(line, args, address),
+            _ => Ok(0),
+        };
+        size.unwrap_or(0)
+    }
 
-        None
+    /* the words a data directive places at `address` */
+    fn parse_directive(
+        line: &str,
+        labels: &HashMap<String, u32>,
+        address: u32,
+    ) -> LineResult<Vec<UmWord>> {
+        let (name, args) = UMAssembler::split_directive(line);
+        match name {
+            ".word" => Ok(vec![UMAssembler::parse_word(line, args, labels)?]),
+            ".words" => UMAssembler::split_args(args)
+                .into_iter()
+                .map(|part| UMAssembler::parse_word(line, part, labels))
+                .collect(),
+            ".string" => UMAssembler::unescape(line, UMAssembler::string_body(line, args)?, '"'),
+            ".zero" => Ok(vec![0; UMAssembler::parse_count(line, args)? as usize]),
+            ".align" => Ok(vec![0; UMAssembler::padding(line, args, address)? as usize]),
+            _ => Err(UMAssembler::error(
+                line,
+                name,
+                &format!("unknown directive `{}`", name),
+            )),
+        }
+    }
+
+    /* the text between the quotes of a `.string` */
+    fn string_body<'a>(line: &str, args: &'a str) -> LineResult<&'a str> {
+        match args.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+            Some(inner) => Ok(inner),
+            None => Err(UMAssembler::error(
+                line,
+                args,
+                &format!("expected a quoted string, found `{}`", args),
+            )),
+        }
+    }
+
+    /* the words of 0 `.align` places at `address` */
+    fn padding(line: &str, args: &str, address: u32) -> LineResult<u32> {
+        match UMAssembler::parse_count(line, args)? {
+            0 => Err(UMAssembler::error(
+                line,
+                args,
+                "alignment must be at least 1",
+            )),
+            align => Ok((align - address % align) % align),
+        }
+    }
+
+    /* a directive's name and the rest of the line */
+    fn split_directive(line: &str) -> (&str, &str) {
+        let name_end = line.find(char::is_whitespace).unwrap_or(line.len());
+        (&line[..name_end], line[name_end..].trim())
+    }
+
+    /* a full 32-bit value or a label */
+    fn parse_word(line: &str, part: &str, labels: &HashMap<String, u32>) -> LineResult<UmWord> {
+        if let Some(value) = UMAssembler::parse_literal(line, part)? {
+            return Ok(value);
+        }
+        if UMAssembler::is_label(part) {
+            return labels.get(part).copied().ok_or_else(|| {
+                UMAssembler::error(line, part, &format!("undefined label `{}`", part))
+            });
+        }
+
+        let reason = match part {
+            "" => String::from("expected a value or label"),
+            _ => format!("invalid value `{}`", part),
+        };
+        Err(UMAssembler::error(line, part, &reason))
+    }
+
+    /* a number of words, kept to what a label could address */
+    fn parse_count(line: &str, part: &str) -> LineResult<u32> {
+        match UMAssembler::parse_value(part) {
+            Some(count) => UMAssembler::expect_value(line, part, count),
+            None => Err(UMAssembler::error(
+                line,
+                part,
+                &format!("expected a count, found `{}`", part),
+            )),
+        }
+    }
+
+    /* one word per character between `quote`s, with escapes resolved */
+    fn unescape(line: &str, inner: &str, quote: char) -> LineResult<Vec<UmWord>> {
+        let mut words = Vec::with_capacity(inner.len());
+        UMAssembler::unescape_with(line, inner, quote, |word| words.push(word))?;
+        Ok(words)
+    }
+
+    /* like `unescape`, handing each word to `emit` instead of keeping it */
+    fn unescape_with(
+        line: &str,
+        inner: &str,
+        quote: char,
+        mut emit: impl FnMut(UmWord),
+    ) -> LineResult<()> {
+        let mut chars = inner.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {}
+                _ if c == quote => {
+                    return Err(UMAssembler::error(
+                        line,
+                        &inner[i..i + 1],
+                        &format!("unescaped `{}`", quote),
+                    ))
+                }
+                /* OUT takes bytes, and a code point above 0x7F is not one */
+                _ if !c.is_ascii() => {
+                    return Err(UMAssembler::error(
+                        line,
+                        &inner[i..i + c.len_utf8()],
+                        &format!("non-ASCII character `{}`, write its bytes with `\\xNN`", c),
+                    ))
+                }
+                _ => {
+                    emit(c as UmWord);
+                    continue;
+                }
+            }
+
+            let (j, escape) = match chars.next() {
+                Some(next) => next,
+                None => return Err(UMAssembler::error(line, &inner[i..], "unfinished escape")),
+            };
+            emit(match escape {
+                'n' => '\n' as UmWord,
+                't' => '\t' as UmWord,
+                'r' => '\r' as UmWord,
+                '0' => 0,
+                '\\' | '\'' | '"' => escape as UmWord,
+                'x' => {
+                    let digits = inner
+                        .get(j + 1..j + 3)
+                        .filter(|d| d.len() == 2 && d.chars().all(|c| c.is_ascii_hexdigit()));
+                    match digits {
+                        Some(digits) => {
+                            chars.nth(1);
+                            u32::from_str_radix(digits, 16).unwrap()
+                        }
+                        None => {
+                            return Err(UMAssembler::error(
+                                line,
+                                &inner[i..j + 1],
+                                "expected two hex digits after `\\x`",
+                            ))
+                        }
+                    }
+                }
+                _ => {
+                    let escape = &inner[i..j + escape.len_utf8()];
+                    return Err(UMAssembler::error(
+                        line,
+                        escape,
+                        &format!("unknown escape `{}`", escape),
+                    ));
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    /* splits `a: b: instr` into the labels defined and the instruction */
+    fn split_labels(line: &str) -> (Vec<&str>, &str) {
+        let mut labels = Vec::new();
+        let mut rest = line.trim();
+        while let Some((name, after)) = rest.split_once(':') {
+            if after.starts_with('=') || !UMAssembler::is_label(name.trim()) {
+                break;
+            }
+            labels.push(name.trim());
+            rest = after.trim();
+        }
+
+        (labels, rest)
     }
 
     /* expects no whitespaces */
     fn parse_reg(line: &str) -> Option<u32> {
-        if line.chars().any(|c| c.is_whitespace()) {
-            return None;
-        }
+        UMAssembler::parse_reg_number(line).filter(|&reg| reg < NUM_REGISTERS)
+    }
 
+    /* rNN for any NN, so registers out of range can be told apart from junk */
+    fn parse_reg_number(line: &str) -> Option<u32> {
         let digits = line.strip_prefix('r')?;
 
-        if digits.is_empty() {
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
             return None;
         }
 
         digits.parse::<u32>().ok()
     }
 
-    pub fn read_asm_code(&mut self, path: &str) -> io::Result<Vec<u32>> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-        let mut instructions = Vec::new();
+    pub fn read_asm_code(&mut self, path: &str) -> Result<Vec<u32>, Vec<AsmError>> {
+        self.read_asm_image(path).map(|image| image.words)
+    }
+
+    /* like `read_asm_code`, keeping labels and line numbers as well */
+    pub fn read_asm_image(&mut self, path: &str) -> Result<Image, Vec<AsmError>> {
+        let file = File::open(path).map_err(|e| {
+            vec![AsmError {
+                path: path.to_string(),
+                line: 0,
+                columns: 0..0,
+                source: String::new(),
+                reason: format!("could not read {}: {}", path, e),
+            }]
+        })?;
+        self.assemble_image(path, BufReader::new(file))
+    }
+
+    /*
+     * assembles ums source from any reader, at most one instruction per line.
+     * Labels are collected in a first pass so they can be used before
+     * they are defined. Every bad line is reported, not just the first;
+     * `path` is only used to label the errors.
+     */
+    pub fn assemble<R: BufRead>(
+        &mut self,
+        path: &str,
+        reader: R,
+    ) -> Result<Vec<u32>, Vec<AsmError>> {
+        self.assemble_image(path, reader).map(|image| image.words)
+    }
 
-        for (i, line) in reader.lines().enumerate() {
-            match UMAssembler::parse_instruction(&line?) {
-                Some(instr) => instructions.push(instr),
-                None => panic!("could not process instruction {}", i),
+    /* labels become symbols, and each instruction remembers its line */
+    pub fn assemble_image<R: BufRead>(
+        &mut self,
+        path: &str,
+        reader: R,
+    ) -> Result<Image, Vec<AsmError>> {
+        let mut lines = Vec::new();
+        for line in reader.lines() {
+            match line {
+                Ok(line) => lines.push(line),
+                Err(e) => {
+                    return Err(vec![AsmError {
+                        path: path.to_string(),
+                        line: lines.len() + 1,
+                        columns: 0..0,
+                        source: String::new(),
+                        reason: format!("could not read line: {}", e),
+                    }])
+                }
             }
         }
 
-        Ok(instructions)
-    }
+        let mut errors = Vec::new();
+        let mut labels = HashMap::new();
+        let mut address = 0;
+        for (i, line) in lines.iter().enumerate() {
+            let (defined, rest) = UMAssembler::split_labels(UMAssembler::strip_comment(line));
+            for name in defined {
+                if labels.insert(name.to_string(), address).is_some() {
+                    errors.push(AsmError::new(
+                        path,
+                        i + 1,
+                        line,
+                        UMAssembler::error(
+                            line,
+                            name,
+                            &format!("label `{}` is defined more than once", name),
+                        ),
+                    ));
+                }
+            }
+            let size = UMAssembler::size_of(rest, address);
+            address = match address.checked_add(size) {
+                Some(address) => address,
+                None => {
+                    /* the second pass would only run out of memory */
+                    errors.push(AsmError::new(
+                        path,
+                        i + 1,
+                        line,
+                        UMAssembler::error(line, rest, "the program does not fit in 2^32 words"),
+                    ));
+                    return Err(errors);
+                }
+            };
+        }
+
+        let mut instructions = Vec::new();
+        let mut line_info = Vec::new();
+        /* the pc given by `.entry`, and the line that gave it */
+        let mut entry = None;
+        for (i, line) in lines.iter().enumerate() {
+            /* blank, comment only and label only lines hold no instruction */
+            let (_, rest) = UMAssembler::split_labels(UMAssembler::strip_comment(line));
+            if rest.is_empty() {
+                continue;
+            }
+            let offset = rest.as_ptr() as usize - line.as_ptr() as usize;
+            let address = instructions.len() as u32;
+            let words = if let (".entry", target) = UMAssembler::split_directive(rest) {
+                match (entry, UMAssembler::parse_word(rest, target, &labels)) {
+                    (None, Ok(pc)) => {
+                        entry = Some((pc, i));
+                        Ok(Vec::new())
+                    }
+                    (Some(_), Ok(_)) => Err(UMAssembler::error(
+                        rest,
+                        rest,
+                        "the entry point is already set",
+                    )),
+                    (_, Err(e)) => Err(e),
+                }
+            } else if rest.starts_with('.') {
+                UMAssembler::parse_directive(rest, &labels, address)
+            } else if let Some(words) = UMAssembler::parse_wide_load(rest) {
+                Ok(words.to_vec())
+            } else {
+                UMAssembler::parse_instruction(rest, &labels).map(|instr| vec![instr])
+            };
+            match words {
+                Ok(words) => {
+                    if !words.is_empty() {
+                        line_info.push(LineInfo {
+                            pc: address,
+                            line: i as u32 + 1,
+                        });
+                    }
+                    instructions.extend(words);
+                }
+                Err(e) => errors.push(AsmError::new(
+                    path,
+                    i + 1,
+                    line,
+                    LineError {
+                        span: e.span.start + offset..e.span.end + offset,
+                        reason: e.reason,
+                    },
+                )),
+            }
+        }
 
-    pub fn write_mach_code(&mut self, program: &Vec<u32>, opath: &str) -> io::Result<()> {
-        let mut file = File::create(opath)?;
-        for instr in program.iter() {
-            let bytes = instr.to_be_bytes(); // big-endian byte array [b0, b1, b2, b3]
-            file.write_all(&bytes)?;
+        if let Some((pc, i)) = entry {
+            if pc as usize >= instructions.len() {
+                let line = &lines[i];
+                let (_, rest) = UMAssembler::split_labels(UMAssembler::strip_comment(line));
+                let reason = format!(
+                    "entry point {} is outside the {} word program",
+                    pc,
+                    instructions.len()
+                );
+                let error = UMAssembler::error(line, rest, &reason);
+                errors.push(AsmError::new(path, i + 1, line, error));
+            }
         }
 
-        Ok(())
+        if errors.is_empty() {
+            let mut symbols: Vec<Symbol> = labels
+                .into_iter()
+                .map(|(name, value)| Symbol { name, value })
+                .collect();
+            symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+            Ok(Image {
+                words: instructions,
+                entry: entry.map_or(0, |(pc, _)| pc),
+                symbols,
+                lines: line_info,
+            })
+        } else {
+            errors.sort_by_key(|e| e.line);
+            Err(errors)
+        }
+    }
+
+    /* writes a raw image, or a container keeping symbols and line numbers */
+    pub fn write_mach_code(
+        &mut self,
+        image: &Image,
+        format: ImageFormat,
+        opath: &str,
+    ) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(opath)?);
+        loader::write_image(&mut file, image, format)?;
+        file.flush()
     }
 }
 #[cfg(test)]
 mod tests {
-    use crate::assembler::UMAssembler;
+    use crate::assembler::{AsmError, UMAssembler};
+    use crate::loader::{LineInfo, Symbol};
     use crate::um::UmOperations;
+    use std::collections::HashMap;
 
     #[test]
     fn test_parse_reg() {
-        for i in 1..100 {
+        for i in 0..8 {
             assert_eq!(UMAssembler::parse_reg(&format!("r{}", i)), Some(i))
         }
+        for i in 8..100 {
+            assert_eq!(UMAssembler::parse_reg(&format!("r{}", i)), None)
+        }
     }
     #[test]
     fn test_parse_instruction() {
         assert_eq!(
-            UMAssembler::parse_instruction("r1 := r2 + r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r1 := r2 + r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::ADD as u32,
                 1,
                 2,
@@ -243,10 +993,10 @@ mod tests {
     #[test]
     fn test_parse_instruction2() {
         assert_eq!(
-            UMAssembler::parse_instruction("r99 := r2 + r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r7 := r2 + r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::ADD as u32,
-                99,
+                7,
                 2,
                 3
             ))
@@ -255,10 +1005,10 @@ mod tests {
     #[test]
     fn test_parse_instruction3() {
         assert_eq!(
-            UMAssembler::parse_instruction("r99 := r2 nand r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r7 := r2 nand r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::NAND as u32,
-                99,
+                7,
                 2,
                 3
             ))
@@ -267,10 +1017,10 @@ mod tests {
     #[test]
     fn test_parse_instruction4() {
         assert_eq!(
-            UMAssembler::parse_instruction("r99 := r2 * r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r7 := r2 * r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::MUL as u32,
-                99,
+                7,
                 2,
                 3
             ))
@@ -279,10 +1029,10 @@ mod tests {
     #[test]
     fn test_parse_instruction5() {
         assert_eq!(
-            UMAssembler::parse_instruction("r99 := r2 / r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r7 := r2 / r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::DIV as u32,
-                99,
+                7,
                 2,
                 3
             ))
@@ -291,10 +1041,10 @@ mod tests {
     #[test]
     fn test_parse_instruction6() {
         assert_eq!(
-            UMAssembler::parse_instruction("r99 := m[r2][r3]"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r7 := m[r2][r3]", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::SLOAD as u32,
-                99,
+                7,
                 2,
                 3
             ))
@@ -303,8 +1053,8 @@ mod tests {
     #[test]
     fn test_parse_instruction7() {
         assert_eq!(
-            UMAssembler::parse_instruction("r1 := r2 if r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r1 := r2 if r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::CMOV as u32,
                 1,
                 2,
@@ -315,8 +1065,8 @@ mod tests {
     #[test]
     fn test_parse_instruction8() {
         assert_eq!(
-            UMAssembler::parse_instruction("r1 := map r6"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("r1 := map r6", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::MAP as u32,
                 0,
                 1,
@@ -327,8 +1077,8 @@ mod tests {
     #[test]
     fn test_parse_instruction9() {
         assert_eq!(
-            UMAssembler::parse_instruction("r1 := 55"),
-            Some(UMAssembler::build_load_value_instruction(
+            UMAssembler::parse_instruction("r1 := 55", &HashMap::new()),
+            Ok(UMAssembler::build_load_value_instruction(
                 UmOperations::LV as u32,
                 1,
                 55
@@ -338,8 +1088,8 @@ mod tests {
     #[test]
     fn test_parse_instruction10() {
         assert_eq!(
-            UMAssembler::parse_instruction("r1 := 0x55"),
-            Some(UMAssembler::build_load_value_instruction(
+            UMAssembler::parse_instruction("r1 := 0x55", &HashMap::new()),
+            Ok(UMAssembler::build_load_value_instruction(
                 UmOperations::LV as u32,
                 1,
                 0x55
@@ -349,8 +1099,8 @@ mod tests {
     #[test]
     fn test_parse_instruction11() {
         assert_eq!(
-            UMAssembler::parse_instruction("r1 := 0b11"),
-            Some(UMAssembler::build_load_value_instruction(
+            UMAssembler::parse_instruction("r1 := 0b11", &HashMap::new()),
+            Ok(UMAssembler::build_load_value_instruction(
                 UmOperations::LV as u32,
                 1,
                 0b11
@@ -360,8 +1110,8 @@ mod tests {
     #[test]
     fn test_parse_instruction12() {
         assert_eq!(
-            UMAssembler::parse_instruction("unmap r1"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("unmap r1", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::UNMAP as u32,
                 0,
                 0,
@@ -372,8 +1122,8 @@ mod tests {
     #[test]
     fn test_parse_instruction13() {
         assert_eq!(
-            UMAssembler::parse_instruction("out r1"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("out r1", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::OUT as u32,
                 0,
                 0,
@@ -384,8 +1134,8 @@ mod tests {
     #[test]
     fn test_parse_instruction14() {
         assert_eq!(
-            UMAssembler::parse_instruction("out r1"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("out r1", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::OUT as u32,
                 0,
                 0,
@@ -396,8 +1146,8 @@ mod tests {
     #[test]
     fn test_parse_instruction15() {
         assert_eq!(
-            UMAssembler::parse_instruction("in r1"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("in r1", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::IN as u32,
                 0,
                 0,
@@ -409,8 +1159,8 @@ mod tests {
     #[test]
     fn test_parse_instruction_sstore() {
         assert_eq!(
-            UMAssembler::parse_instruction("m[r1][r2] := r3"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("m[r1][r2] := r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::SSTORE as u32,
                 1,
                 2,
@@ -422,8 +1172,8 @@ mod tests {
     #[test]
     fn test_parse_instruction_lp() {
         assert_eq!(
-            UMAssembler::parse_instruction("goto m[r2][r5]"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("goto m[r2][r5]", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::LOADP as u32,
                 0,
                 2,
@@ -434,8 +1184,8 @@ mod tests {
     #[test]
     fn test_parse_instruction_halt() {
         assert_eq!(
-            UMAssembler::parse_instruction("halt"),
-            Some(UMAssembler::build_three_reg_instruction(
+            UMAssembler::parse_instruction("halt", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
                 UmOperations::HALT as u32,
                 0,
                 0,
@@ -495,4 +1245,482 @@ mod tests {
             0xD7FFFFFFu32
         );
     }
+
+    #[test]
+    fn test_split_labels() {
+        assert_eq!(UMAssembler::split_labels("loop:"), (vec!["loop"], ""));
+        assert_eq!(
+            UMAssembler::split_labels("a: b : r1 := 5"),
+            (vec!["a", "b"], "r1 := 5")
+        );
+        assert_eq!(UMAssembler::split_labels("r1 := 5"), (vec![], "r1 := 5"));
+        assert_eq!(UMAssembler::split_labels("r1: halt"), (vec![], "r1: halt"));
+        assert_eq!(
+            UMAssembler::split_labels("map: halt"),
+            (vec![], "map: halt")
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_label() {
+        let labels = HashMap::from([(String::from("nandy"), 7)]);
+        assert_eq!(
+            UMAssembler::parse_instruction("r3 := nandy", &labels),
+            Ok(UMAssembler::build_load_value_instruction(
+                UmOperations::LV as u32,
+                3,
+                7
+            ))
+        );
+        assert_eq!(
+            UMAssembler::parse_instruction("r3 := missing", &labels)
+                .unwrap_err()
+                .reason,
+            "undefined label `missing`"
+        );
+    }
+
+    #[test]
+    fn test_undefined_label_like_an_operator() {
+        /* each of these names starts with or contains an operator */
+        for (source, name) in [
+            ("r1 := notify", "notify"),
+            ("r1 := diff", "diff"),
+            ("r1 := nandy", "nandy"),
+            ("r1 := r2nand", "r2nand"),
+            ("r1 := mapped", "mapped"),
+        ] {
+            let error = UMAssembler::parse_instruction(source, &HashMap::new()).unwrap_err();
+            assert_eq!(
+                error.reason,
+                format!("undefined label `{}`", name),
+                "{}",
+                source
+            );
+            assert_eq!(&source[error.span], name);
+        }
+    }
+
+    #[test]
+    fn test_labels_do_not_shadow_instructions() {
+        let source = "mapr1: r2ifr3: halt\nr2 := map r1\nr1 := r2 if r3\nr4 := mapr1\n";
+        let program = UMAssembler {}
+            .assemble("test.ums", source.as_bytes())
+            .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                UMAssembler::build_three_reg_instruction(UmOperations::HALT as u32, 0, 0, 0),
+                UMAssembler::build_three_reg_instruction(UmOperations::MAP as u32, 0, 2, 1),
+                UMAssembler::build_three_reg_instruction(UmOperations::CMOV as u32, 1, 2, 3),
+                UMAssembler::build_load_value_instruction(UmOperations::LV as u32, 4, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_forward_and_backward_labels() {
+        let source = "\
+start: r1 := done
+goto m[r0][r1]
+loop:
+    r2 := loop
+done: r3 := start
+halt
+";
+        assert_eq!(
+            UMAssembler {}
+                .assemble("test.ums", source.as_bytes())
+                .unwrap(),
+            vec![
+                UMAssembler::build_load_value_instruction(UmOperations::LV as u32, 1, 3),
+                UMAssembler::build_three_reg_instruction(UmOperations::LOADP as u32, 0, 0, 1),
+                UMAssembler::build_load_value_instruction(UmOperations::LV as u32, 2, 2),
+                UMAssembler::build_load_value_instruction(UmOperations::LV as u32, 3, 0),
+                UMAssembler::build_three_reg_instruction(UmOperations::HALT as u32, 0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_duplicate_label() {
+        assert!(UMAssembler {}
+            .assemble("test.ums", "a: halt\na: halt\n".as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn test_assemble_reports_every_error() {
+        let source = "r1 := r2 % r3\nhalt\n\tout rx\n";
+        let errors = UMAssembler {}
+            .assemble("bad.ums", source.as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                AsmError {
+                    path: String::from("bad.ums"),
+                    line: 1,
+                    columns: 9..10,
+                    source: String::from("r1 := r2 % r3"),
+                    reason: String::from("unknown binop `%`"),
+                },
+                AsmError {
+                    path: String::from("bad.ums"),
+                    line: 3,
+                    columns: 5..7,
+                    source: String::from("\tout rx"),
+                    reason: String::from("expected a register, found `rx`"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_asm_error_display() {
+        let error = AsmError {
+            path: String::from("bad.ums"),
+            line: 3,
+            columns: 6..8,
+            source: String::from("r1 := rx"),
+            reason: String::from("expected a register, found `rx`"),
+        };
+        assert_eq!(
+            error.to_string(),
+            "error: expected a register, found `rx`\n \
+             --> bad.ums:3:7\n  \
+             |\n\
+             3 | r1 := rx\n  \
+             |       ^^"
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_register_out_of_range() {
+        for (line, columns) in [
+            ("r99 := r2 + r3", 0..3),
+            ("r1 := r2 nand r8", 14..16),
+            ("m[r1][r12] := r3", 6..9),
+            ("out r8", 4..6),
+        ] {
+            let error = UMAssembler::parse_instruction(line, &HashMap::new()).unwrap_err();
+            assert_eq!(error.span, columns, "{}", line);
+            assert!(error.reason.contains("out of range 0..7"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse_instruction_value_too_large() {
+        assert_eq!(
+            UMAssembler::parse_instruction("r1 := 0x1FFFFFF", &HashMap::new()),
+            Ok(UMAssembler::build_load_value_instruction(
+                UmOperations::LV as u32,
+                1,
+                0x1FFFFFF
+            ))
+        );
+        assert_eq!(
+            UMAssembler::parse_instruction("r1 := 0x2000000", &HashMap::new())
+                .unwrap_err()
+                .reason,
+            "value 0x2000000 exceeds 25 bits, and only values from 0xFE000000 up can be \
+             loaded as two instructions"
+        );
+        assert!(UMAssembler::parse_instruction("r1 := 4294967296", &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_parse_instruction_char_literals() {
+        let labels = HashMap::new();
+        for (source, value) in [
+            ("r1 := 'a'", 97),
+            ("r1 := ' '", 32),
+            ("r1 := '+'", 43),
+            ("r1 := '\\n'", 10),
+            ("r1 := '\\t'", 9),
+            ("r1 := '\\\\'", 92),
+            ("r1 := '\\''", 39),
+            ("r1 := '\\x41'", 65),
+        ] {
+            assert_eq!(
+                UMAssembler::parse_instruction(source, &labels),
+                Ok(UMAssembler::build_load_value_instruction(
+                    UmOperations::LV as u32,
+                    1,
+                    value
+                )),
+                "{}",
+                source
+            );
+        }
+
+        for (source, span, reason) in [
+            ("r1 := 'ab'", "'ab'", "`'ab'` is not a single character"),
+            ("r1 := ''", "''", "`''` is not a single character"),
+            ("r1 := 'a", "'a", "unterminated character literal"),
+            ("r1 := '\\q'", "\\q", "unknown escape `\\q`"),
+            ("r1 := '\\x4'", "\\x", "expected two hex digits after `\\x`"),
+            (
+                "r1 := 'é'",
+                "é",
+                "non-ASCII character `é`, write its bytes with `\\xNN`",
+            ),
+            (
+                "r1 := '€'",
+                "€",
+                "non-ASCII character `€`, write its bytes with `\\xNN`",
+            ),
+        ] {
+            let error = UMAssembler::parse_instruction(source, &labels).unwrap_err();
+            assert_eq!(&source[error.span], span, "{}", source);
+            assert_eq!(error.reason, reason);
+        }
+    }
+
+    #[test]
+    fn test_parse_value_negative() {
+        assert_eq!(UMAssembler::parse_value("-1"), Some(0xFFFFFFFF));
+        assert_eq!(UMAssembler::parse_value("-2147483648"), Some(0x80000000));
+        assert_eq!(UMAssembler::parse_value("-0"), Some(0));
+        assert_eq!(UMAssembler::parse_value("-2147483649"), None);
+        assert_eq!(UMAssembler::parse_value("-+1"), None);
+        assert_eq!(
+            UMAssembler::parse_instruction("r1 := -33554433", &HashMap::new())
+                .unwrap_err()
+                .reason,
+            "negative value -33554433 is below -33554432"
+        );
+    }
+
+    #[test]
+    fn test_wide_load() {
+        assert_eq!(
+            UMAssembler::parse_wide_load("r1 := -1"),
+            Some([0xD2000000, 0x60000049])
+        );
+        assert_eq!(
+            UMAssembler::parse_wide_load("r7:=-33554432"),
+            Some([0xDFFFFFFF, 0x600001FF])
+        );
+        assert_eq!(
+            UMAssembler::parse_wide_load("r2 := 0xFFFFFFFF"),
+            Some([0xD4000000, 0x60000092])
+        );
+        assert_eq!(
+            UMAssembler::parse_wide_load("r1 := 4261412864"),
+            Some([0xD3FFFFFF, 0x60000049])
+        );
+        assert_eq!(UMAssembler::parse_wide_load("r1 := -33554433"), None);
+        assert_eq!(UMAssembler::parse_wide_load("r1 := 0xFDFFFFFF"), None);
+        assert_eq!(UMAssembler::parse_wide_load("r1 := 0x1FFFFFF"), None);
+        assert_eq!(UMAssembler::parse_wide_load("r1 := -0"), None);
+        assert_eq!(UMAssembler::parse_wide_load("r1 := r2 + r3"), None);
+        assert_eq!(UMAssembler::parse_wide_load("m[r1][r2] := -1"), None);
+
+        /* the label after it accounts for both words */
+        let source = "r3 := -5\nend: r1 := end\n";
+        let program = UMAssembler {}
+            .assemble("test.ums", source.as_bytes())
+            .unwrap();
+        assert_eq!(program, vec![0xD6000004, 0x600000DB, 0xD2000002]);
+    }
+
+    #[test]
+    fn test_assemble_literals_in_data() {
+        let source = ".words -1, ',', ' ', '#' ; comment\nr2 := ';' // comment\n";
+        let program = UMAssembler {}
+            .assemble("test.ums", source.as_bytes())
+            .unwrap();
+        assert_eq!(program, vec![0xFFFFFFFF, 44, 32, 35, 0xD400003B]);
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(UMAssembler::strip_comment("halt // stop"), "halt ");
+        assert_eq!(UMAssembler::strip_comment("halt # stop ; here"), "halt ");
+        assert_eq!(UMAssembler::strip_comment("; r1 := 5"), "");
+        assert_eq!(UMAssembler::strip_comment("r1 := r2 / r3"), "r1 := r2 / r3");
+        assert_eq!(
+            UMAssembler::strip_comment(".string \"a; \\\"#\" // b"),
+            ".string \"a; \\\"#\" "
+        );
+    }
+
+    #[test]
+    fn test_parse_instruction_tabs() {
+        assert_eq!(
+            UMAssembler::parse_instruction("r1\t:=\tr2 \t+  r3", &HashMap::new()),
+            Ok(UMAssembler::build_three_reg_instruction(
+                UmOperations::ADD as u32,
+                1,
+                2,
+                3
+            ))
+        );
+    }
+
+    #[test]
+    fn test_assemble_comments_and_blank_lines() {
+        let source = "\
+# print a newline
+\tr1 := 10 // newline
+
+; then stop
+end:\t; nothing else
+\thalt\t\t# done
+";
+        assert_eq!(
+            UMAssembler {}
+                .assemble("test.ums", source.as_bytes())
+                .unwrap(),
+            vec![
+                UMAssembler::build_load_value_instruction(UmOperations::LV as u32, 1, 10),
+                UMAssembler::build_three_reg_instruction(UmOperations::HALT as u32, 0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_image() {
+        let source = "// counts down\nstart: r1 := 3\n\nloop: halt\n";
+        let image = UMAssembler {}
+            .assemble_image("test.ums", source.as_bytes())
+            .unwrap();
+        assert_eq!(
+            image.symbols,
+            vec![
+                Symbol {
+                    name: String::from("start"),
+                    value: 0
+                },
+                Symbol {
+                    name: String::from("loop"),
+                    value: 1
+                }
+            ]
+        );
+        assert_eq!(
+            image.lines,
+            vec![LineInfo { pc: 0, line: 2 }, LineInfo { pc: 1, line: 4 }]
+        );
+    }
+
+    #[test]
+    fn test_assemble_data_directives() {
+        let source = "\
+            r1 := table\n\
+            halt\n\
+            .align 4\n\
+            table: .words 1, 0xDEADBEEF, end\n\
+            msg: .string \"a b\\n\\x41\\\"\"\n\
+            .zero 2\n\
+            end: .word msg\n";
+        let image = UMAssembler {}
+            .assemble_image("test.ums", source.as_bytes())
+            .unwrap();
+        assert_eq!(
+            image.words,
+            vec![0xD2000004, 0x70000000, 0, 0, 1, 0xDEADBEEF, 15, 97, 32, 98, 10, 65, 34, 0, 0, 7]
+        );
+        assert_eq!(
+            image.lines,
+            vec![
+                LineInfo { pc: 0, line: 1 },
+                LineInfo { pc: 1, line: 2 },
+                LineInfo { pc: 2, line: 3 },
+                LineInfo { pc: 4, line: 4 },
+                LineInfo { pc: 7, line: 5 },
+                LineInfo { pc: 13, line: 6 },
+                LineInfo { pc: 15, line: 7 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_directive_errors() {
+        let source = ".byte 1\n.words 1,,2\n.string \"\\q\"\n.zero lots\n.word nowhere\n";
+        let errors = UMAssembler {}
+            .assemble("bad.ums", source.as_bytes())
+            .unwrap_err();
+        let reasons: Vec<(usize, &str)> = errors
+            .iter()
+            .map(|e| (e.line, &e.source[e.columns.clone()]))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, ".byte"),
+                (2, ""),
+                (3, "\\q"),
+                (4, "lots"),
+                (5, "nowhere")
+            ]
+        );
+        assert_eq!(errors[2].reason, "unknown escape `\\q`");
+    }
+
+    #[test]
+    fn test_assemble_string_non_ascii() {
+        let source = ".string \"caf\\xC3\\xA9\"\n.string \"5 €\"\n";
+        let errors = UMAssembler {}
+            .assemble("bad.ums", source.as_bytes())
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(&errors[0].source[errors[0].columns.clone()], "€");
+        assert_eq!(
+            errors[0].reason,
+            "non-ASCII character `€`, write its bytes with `\\xNN`"
+        );
+
+        let program = UMAssembler {}
+            .assemble("ok.ums", ".string \"caf\\xC3\\xA9\"\n".as_bytes())
+            .unwrap();
+        assert_eq!(program, vec![0x63, 0x61, 0x66, 0xC3, 0xA9]);
+    }
+
+    #[test]
+    fn test_assemble_past_u32_max() {
+        /* 129 of these come to more than 2^32 words, which is never built */
+        let source = ".zero 0x1FFFFFF\n".repeat(129);
+        let errors = UMAssembler {}
+            .assemble("huge.ums", source.as_bytes())
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 129);
+        assert_eq!(errors[0].reason, "the program does not fit in 2^32 words");
+    }
+
+    #[test]
+    fn test_assemble_entry() {
+        let source = "msg: .string \"hi\"\n.entry start\nstart: halt\n";
+        let image = UMAssembler {}
+            .assemble_image("entry.ums", source.as_bytes())
+            .unwrap();
+        assert_eq!(image.entry, 2);
+        assert_eq!(image.words.len(), 3);
+
+        let source = ".entry 0\n.entry 1\nhalt\nstart: .entry start\n";
+        let errors = UMAssembler {}
+            .assemble_image("entry.ums", source.as_bytes())
+            .unwrap_err();
+        let reasons: Vec<(usize, &str)> =
+            errors.iter().map(|e| (e.line, e.reason.as_str())).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (2, "the entry point is already set"),
+                (4, "the entry point is already set")
+            ]
+        );
+
+        let errors = UMAssembler {}
+            .assemble_image("entry.ums", "halt\n.entry 1 // past halt\n".as_bytes())
+            .unwrap_err();
+        assert_eq!(
+            errors[0].reason,
+            "entry point 1 is outside the 1 word program"
+        );
+        assert_eq!(&errors[0].source[errors[0].columns.clone()], ".entry 1");
+    }
 }
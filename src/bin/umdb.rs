@@ -0,0 +1,37 @@
+use std::{
+    env,
+    io::{self, BufRead, Error, ErrorKind, Write},
+};
+use um::debugger::Debugger;
+use um::um::UM;
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: umdb <program.um>",
+        ));
+    }
+
+    let mut machine = UM::new();
+    machine
+        .init_program(&args[1])
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", args[1], e)))?;
+    let mut debugger = Debugger::new(machine, io::stdout());
+
+    /* the program's IN shares stdin with the debugger prompt */
+    let stdin = io::stdin();
+    debugger.show_pc()?;
+    loop {
+        print!("(umdb) ");
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 || !debugger.command(&line)? {
+            break;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::{
+    env,
+    fs::File,
+    io::{self, BufWriter, Error, ErrorKind, Write},
+};
+use um::disassembler;
+use um::loader::{self, LoadError};
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let annotate = args.iter().any(|arg| arg == "-a");
+    let paths: Vec<&String> = args.iter().filter(|arg| *arg != "-a").collect();
+    if paths.len() != 1 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Usage: umdis [-a] <program.um>",
+        ));
+    }
+
+    let program = File::open(paths[0])
+        .map_err(LoadError::from)
+        .and_then(loader::read_program)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", paths[0], e)))?;
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    disassembler::write_listing(&mut out, &program, annotate)?;
+    out.flush()
+}
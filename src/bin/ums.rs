@@ -1,32 +1,161 @@
 use std::{
     env,
-    io::{self, Error, ErrorKind},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
 };
-use um::assembler;
-
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "Not enough arguments provided.",
-        ));
-    }
-
-    for i in 1..args.len() {
-        let mut assembler_module = assembler::UMAssembler {};
-        match args[i].split_once(".") {
-            // Some ((l, r)) => eprintln!("{} {}", l, r),
-            // None => eprint!("yo")
-            Some((base, extension)) if extension == "ums" => {
-                println!("Writing {}.um", base);
-                let program = assembler_module.read_asm_code(&args[i])?;
-                let opath = String::from(base) + ".um";
-                assembler_module.write_mach_code(&program, &opath)?;
+use um::assembler::{AsmError, UMAssembler};
+use um::loader::{self, Image, ImageFormat};
+
+const USAGE: &str = "\
+Usage: ums [options] <file.ums>...
+A file named `-` is read from stdin, and its image goes to stdout.
+  -c                  write a container that keeps labels and line numbers
+  -o <file>           write the image to <file>, or to stdout if it is `-`
+  -d, --out-dir <dir> write each image into <dir>
+  --check             only report errors, without writing anything";
+
+struct Options {
+    format: ImageFormat,
+    output: Option<String>,
+    out_dir: Option<PathBuf>,
+    check: bool,
+    inputs: Vec<String>,
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("ums: {}\n{}", problem, USAGE);
+    process::exit(1);
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        format: ImageFormat::Raw,
+        output: None,
+        out_dir: None,
+        check: false,
+        inputs: Vec::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => value.clone(),
+            None => usage(&format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
             }
-            _ => eprintln!("Warning: Skipping non ums file"),
+            "-c" => options.format = ImageFormat::Container,
+            "-o" => options.output = Some(value()),
+            "-d" | "--out-dir" => options.out_dir = Some(PathBuf::from(value())),
+            "--check" => options.check = true,
+            "-" => options.inputs.push(arg.clone()),
+            _ if arg.starts_with('-') => usage(&format!("unknown option `{}`", arg)),
+            _ => options.inputs.push(arg.clone()),
         }
     }
 
-    Ok(())
+    if options.inputs.is_empty() {
+        usage("no source files given");
+    }
+    if options.output.is_some() && options.inputs.len() > 1 {
+        usage("-o can only be used with one source file");
+    }
+    if options.output.is_some() && options.out_dir.is_some() {
+        usage("-o and --out-dir cannot be used together");
+    }
+    options
+}
+
+/* where the image for `input` goes, or None for stdout */
+fn output_path(options: &Options, input: &str) -> Option<PathBuf> {
+    match &options.output {
+        Some(output) if output == "-" => None,
+        Some(output) => Some(PathBuf::from(output)),
+        None if input == "-" => None,
+        None => {
+            /* foo/bar.ums becomes foo/bar.um, or <dir>/bar.um */
+            let path = Path::new(input).with_extension("um");
+            match (&options.out_dir, path.file_name()) {
+                (Some(dir), Some(name)) => Some(dir.join(name)),
+                _ => Some(path),
+            }
+        }
+    }
+}
+
+fn assemble(input: &str) -> Result<Image, Vec<AsmError>> {
+    let mut assembler = UMAssembler {};
+    if input == "-" {
+        assembler.assemble_image("<stdin>", io::stdin().lock())
+    } else {
+        assembler.read_asm_image(input)
+    }
+}
+
+fn write(image: &Image, format: ImageFormat, path: Option<&Path>) -> io::Result<()> {
+    let mut out: BufWriter<Box<dyn Write>> = match path {
+        Some(path) => BufWriter::new(Box::new(File::create(path)?)),
+        None => BufWriter::new(Box::new(io::stdout().lock())),
+    };
+    loader::write_image(&mut out, image, format)?;
+    out.flush()
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_args(&args);
+
+    let mut failed = false;
+    for input in &options.inputs {
+        let explicit = options.output.is_some() || input == "-";
+        if !explicit && Path::new(input).extension().is_none_or(|ext| ext != "ums") {
+            eprintln!("Warning: Skipping non ums file {}", input);
+            continue;
+        }
+
+        let name = if input == "-" { "<stdin>" } else { input };
+        let image = match assemble(input) {
+            Ok(image) => image,
+            Err(errors) => {
+                for error in errors.iter() {
+                    eprintln!("{}\n", error);
+                }
+                eprintln!(
+                    "error: could not assemble {} due to {} previous error{}",
+                    name,
+                    errors.len(),
+                    if errors.len() == 1 { "" } else { "s" }
+                );
+                failed = true;
+                continue;
+            }
+        };
+        /* a raw image always starts at 0 */
+        if image.entry != 0 && options.format == ImageFormat::Raw {
+            eprintln!("error: {} uses .entry, which needs a container (-c)", name);
+            failed = true;
+            continue;
+        }
+        if options.check {
+            continue;
+        }
+
+        let path = output_path(&options, input);
+        if let Some(path) = &path {
+            eprintln!("Writing {}", path.display());
+        }
+        if let Err(e) = write(&image, options.format, path.as_deref()) {
+            let name = path.as_deref().unwrap_or(Path::new("<stdout>"));
+            eprintln!("error: could not write {}: {}", name.display(), e);
+            failed = true;
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
 }
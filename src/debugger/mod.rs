@@ -0,0 +1,364 @@
+use crate::disassembler::disassemble;
+use crate::um::{HaltReason, StepResult, UmWord, UM};
+use crate::umio::{StdIo, UmIo};
+use std::collections::BTreeSet;
+use std::io::{self, Stdout, Write};
+
+const HELP: &str = "\
+commands:
+  s, step [n]            execute n instructions (default 1)
+  c, continue            run until a breakpoint, watchpoint, halt or fault
+  b, break <pc>          set a breakpoint
+  d, delete <pc>         remove a breakpoint
+  w, watch <seg> <off>   stop when m[seg][off] changes
+  unwatch <seg> <off>    remove a watchpoint
+  i, info                list breakpoints and watchpoints
+  r, regs                dump registers and pc
+  x <seg> [off [count]]  dump words of a segment
+  l, list [n]            disassemble n instructions around the pc
+  h, help                show this message
+  q, quit                exit the debugger
+numbers may be decimal or 0x hex";
+
+struct Watchpoint {
+    segment: usize,
+    offset: usize,
+    /* None while the word is unmapped */
+    last: Option<UmWord>,
+}
+
+/* The state behind umdb's prompt: a machine, its breakpoints and watchpoints */
+pub struct Debugger<IO: UmIo = StdIo, W: Write = Stdout> {
+    pub machine: UM<IO>,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Watchpoint>,
+    /* set once the machine halts or faults */
+    finished: bool,
+    /* where the debugger's own messages go */
+    out: W,
+}
+
+fn parse_number(arg: &str) -> Option<usize> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => arg.parse::<usize>().ok(),
+    }
+}
+
+fn show_word(word: Option<UmWord>) -> String {
+    match word {
+        Some(word) => format!("0x{:08x}", word),
+        None => String::from("<unmapped>"),
+    }
+}
+
+impl<IO: UmIo, W: Write> Debugger<IO, W> {
+    pub fn new(machine: UM<IO>, out: W) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            finished: false,
+            out,
+        }
+    }
+
+    /* true once the program has halted or faulted */
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    fn read_word(&self, segment: usize, offset: usize) -> Option<UmWord> {
+        self.machine
+            .memory
+            .segments
+            .get(segment)?
+            .as_ref()?
+            .get(offset)
+            .copied()
+    }
+
+    /* returns true if the machine should stop */
+    fn single_step(&mut self) -> io::Result<bool> {
+        if self.finished {
+            writeln!(self.out, "The program is not running.")?;
+            return Ok(true);
+        }
+
+        let result = self.machine.step();
+        /* keep the program's output in order with the debugger's */
+        let _ = self.machine.io.flush();
+
+        match result {
+            StepResult::Running => {}
+            StepResult::Halted(HaltReason::Fault(fault)) => {
+                writeln!(self.out, "{}", fault)?;
+                self.finished = true;
+                return Ok(true);
+            }
+            StepResult::Halted(reason) => {
+                writeln!(self.out, "Program stopped: {}", reason)?;
+                self.finished = true;
+                return Ok(true);
+            }
+            StepResult::WaitingForInput | StepResult::OutOfFuel => return Ok(true),
+        }
+
+        let mut stop = false;
+        for i in 0..self.watchpoints.len() {
+            let (segment, offset) = (self.watchpoints[i].segment, self.watchpoints[i].offset);
+            let current = self.read_word(segment, offset);
+            let watch = &mut self.watchpoints[i];
+            if current != watch.last {
+                writeln!(
+                    self.out,
+                    "Watchpoint m[{}][{}]: {} -> {}",
+                    segment,
+                    offset,
+                    show_word(watch.last),
+                    show_word(current)
+                )?;
+                watch.last = current;
+                stop = true;
+            }
+        }
+
+        Ok(stop)
+    }
+
+    fn step(&mut self, count: usize) -> io::Result<()> {
+        for _ in 0..count {
+            if self.single_step()? {
+                break;
+            }
+        }
+        self.show_pc()
+    }
+
+    fn cont(&mut self) -> io::Result<()> {
+        loop {
+            if self.single_step()? {
+                break;
+            }
+            if self.breakpoints.contains(&self.machine.pc) {
+                writeln!(self.out, "Breakpoint at pc {}", self.machine.pc)?;
+                break;
+            }
+        }
+        self.show_pc()
+    }
+
+    /* prints the instruction about to run */
+    pub fn show_pc(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let pc = self.machine.pc;
+        match self.read_word(0, pc) {
+            Some(word) => writeln!(self.out, "{:>8}: {}", pc, disassemble(word)),
+            None => writeln!(self.out, "{:>8}: <outside segment 0>", pc),
+        }
+    }
+
+    fn list(&mut self, count: usize) -> io::Result<()> {
+        let start = self.machine.pc.saturating_sub(count / 2);
+        for pc in start..start + count {
+            let word = match self.read_word(0, pc) {
+                Some(word) => word,
+                None => break,
+            };
+            let marker = if pc == self.machine.pc { "=>" } else { "  " };
+            let brk = if self.breakpoints.contains(&pc) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(
+                self.out,
+                "{}{}{:>8}: {:08x}  {}",
+                marker,
+                brk,
+                pc,
+                word,
+                disassemble(word)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn regs(&mut self) -> io::Result<()> {
+        for (i, reg) in self.machine.registers.iter().enumerate() {
+            writeln!(self.out, "r{} = 0x{:08x} ({})", i, reg, reg)?;
+        }
+        writeln!(self.out, "pc = {}", self.machine.pc)
+    }
+
+    fn examine(&mut self, segment: usize, offset: usize, count: usize) -> io::Result<()> {
+        let words = match self.machine.memory.segments.get(segment) {
+            Some(Some(words)) => words,
+            _ => return writeln!(self.out, "No segment mapped at {}", segment),
+        };
+        writeln!(self.out, "segment {} has {} words", segment, words.len())?;
+        let end = words.len().min(offset.saturating_add(count));
+        for (i, chunk) in words[offset.min(end)..end].chunks(4).enumerate() {
+            write!(self.out, "{:>8}:", offset + i * 4)?;
+            for word in chunk {
+                write!(self.out, " {:08x}", word)?;
+            }
+            writeln!(self.out)?;
+        }
+
+        Ok(())
+    }
+
+    fn info(&mut self) -> io::Result<()> {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            writeln!(self.out, "No breakpoints or watchpoints.")?;
+        }
+        for pc in self.breakpoints.iter() {
+            writeln!(self.out, "breakpoint at pc {}", pc)?;
+        }
+        for watch in self.watchpoints.iter() {
+            writeln!(
+                self.out,
+                "watchpoint on m[{}][{}] = {}",
+                watch.segment,
+                watch.offset,
+                show_word(watch.last)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /* runs one line typed at the prompt, returning false once the user wants to quit */
+    pub fn command(&mut self, line: &str) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let args: Vec<Option<usize>> = words.iter().skip(1).map(|w| parse_number(w)).collect();
+        let arg = |i: usize| args.get(i).copied().flatten();
+
+        match (words.first().copied(), args.len()) {
+            (None, _) => {}
+            (Some("q" | "quit"), _) => return Ok(false),
+            (Some("h" | "help"), _) => writeln!(self.out, "{}", HELP)?,
+            (Some("s" | "step"), 0) => self.step(1)?,
+            (Some("s" | "step"), 1) if arg(0).is_some() => self.step(arg(0).unwrap())?,
+            (Some("c" | "continue"), 0) => self.cont()?,
+            (Some("b" | "break"), 1) if arg(0).is_some() => {
+                self.breakpoints.insert(arg(0).unwrap());
+            }
+            (Some("d" | "delete"), 1) if arg(0).is_some() => {
+                if !self.breakpoints.remove(&arg(0).unwrap()) {
+                    writeln!(self.out, "No breakpoint at pc {}", arg(0).unwrap())?;
+                }
+            }
+            (Some("w" | "watch"), 2) if arg(0).is_some() && arg(1).is_some() => {
+                let (segment, offset) = (arg(0).unwrap(), arg(1).unwrap());
+                let last = self.read_word(segment, offset);
+                self.watchpoints.push(Watchpoint {
+                    segment,
+                    offset,
+                    last,
+                });
+            }
+            (Some("unwatch"), 2) if arg(0).is_some() && arg(1).is_some() => {
+                let (segment, offset) = (arg(0).unwrap(), arg(1).unwrap());
+                self.watchpoints
+                    .retain(|w| w.segment != segment || w.offset != offset);
+            }
+            (Some("i" | "info"), 0) => self.info()?,
+            (Some("r" | "regs"), 0) => self.regs()?,
+            (Some("x"), 1..=3) if args.iter().all(|a| a.is_some()) => {
+                self.examine(arg(0).unwrap(), arg(1).unwrap_or(0), arg(2).unwrap_or(16))?
+            }
+            (Some("l" | "list"), 0) => self.list(10)?,
+            (Some("l" | "list"), 1) if arg(0).is_some() => self.list(arg(0).unwrap())?,
+            _ => writeln!(self.out, "Bad command `{}`, try `help`", line.trim())?,
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::Debugger;
+    use crate::test_util::{load_value, three_reg};
+    use crate::um::{UmOperations, UM};
+    use crate::umio::MemoryIo;
+
+    /* maps a one word segment, stores 7 in it, then halts */
+    fn debugger() -> Debugger<MemoryIo, Vec<u8>> {
+        let mut machine = UM::with_io(MemoryIo::default());
+        machine.load_words(vec![
+            load_value(1, 1),
+            three_reg(UmOperations::MAP, 0, 2, 1),
+            load_value(3, 7),
+            load_value(4, 0),
+            three_reg(UmOperations::SSTORE, 2, 4, 3),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        Debugger::new(machine, Vec::new())
+    }
+
+    /* runs `line` and returns what the debugger printed */
+    fn run(debugger: &mut Debugger<MemoryIo, Vec<u8>>, line: &str) -> String {
+        debugger.out.clear();
+        assert!(debugger.command(line).unwrap());
+        String::from_utf8(debugger.out.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_command_parsing() {
+        let mut debugger = debugger();
+        assert_eq!(run(&mut debugger, ""), "");
+        assert!(run(&mut debugger, "help").starts_with("commands:"));
+        assert_eq!(run(&mut debugger, "b 0x3"), "");
+        assert_eq!(run(&mut debugger, "info"), "breakpoint at pc 3\n");
+        assert_eq!(run(&mut debugger, "d 4"), "No breakpoint at pc 4\n");
+        assert_eq!(run(&mut debugger, "d 3"), "");
+        assert_eq!(run(&mut debugger, "b x"), "Bad command `b x`, try `help`\n");
+        assert_eq!(
+            run(&mut debugger, "step 1 2"),
+            "Bad command `step 1 2`, try `help`\n"
+        );
+        assert_eq!(run(&mut debugger, "s 2"), "       2: r3 := 7\n");
+        assert!(run(&mut debugger, "regs").contains("r1 = 0x00000001 (1)\n"));
+        assert!(!debugger.command("quit").unwrap());
+    }
+
+    #[test]
+    fn test_cont_stops_at_breakpoint() {
+        let mut debugger = debugger();
+        run(&mut debugger, "break 4");
+        assert_eq!(
+            run(&mut debugger, "c"),
+            "Breakpoint at pc 4\n       4: m[r2][r4] := r3\n"
+        );
+        assert_eq!(debugger.machine.pc, 4);
+        assert_eq!(run(&mut debugger, "c"), "Program stopped: halted\n");
+        assert!(debugger.finished());
+        assert_eq!(run(&mut debugger, "s"), "The program is not running.\n");
+    }
+
+    #[test]
+    fn test_watchpoint_fires_on_change() {
+        let mut debugger = debugger();
+        /* the segment isn't mapped yet, so the watch starts out unmapped */
+        run(&mut debugger, "watch 1 0");
+        assert_eq!(
+            run(&mut debugger, "c"),
+            "Watchpoint m[1][0]: <unmapped> -> 0x00000000\n       2: r3 := 7\n"
+        );
+        assert_eq!(
+            run(&mut debugger, "c"),
+            "Watchpoint m[1][0]: 0x00000000 -> 0x00000007\n       5: halt\n"
+        );
+        run(&mut debugger, "unwatch 1 0");
+        assert_eq!(
+            run(&mut debugger, "info"),
+            "No breakpoints or watchpoints.\n"
+        );
+    }
+}
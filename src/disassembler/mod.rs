@@ -0,0 +1,100 @@
+use crate::um::{UmOp, UmOperations, UmWord};
+use std::io::{self, Write};
+
+/* bits the assembler can set for each opcode */
+fn used_bits(op: UmOperations) -> UmWord {
+    match op {
+        UmOp::LV => 0xFFFFFFFF,
+        UmOp::HALT => 0xF0000000,
+        UmOp::MAP | UmOp::LOADP => 0xF000003F,
+        UmOp::UNMAP | UmOp::OUT | UmOp::IN => 0xF0000007,
+        _ => 0xF00001FF,
+    }
+}
+
+/*
+ * Turns a single machine word back into umsgrammar syntax. Words the
+ * assembler could not have written, with an invalid opcode or stray bits
+ * set, come out as `.word` so that the listing reassembles to the same
+ * words.
+ */
+pub fn disassemble(word: UmWord) -> String {
+    let op = match UmOperations::decode(word) {
+        Some(op) if word & !used_bits(op) == 0 => op,
+        _ => return format!(".word 0x{:08x}", word),
+    };
+    let (a, b, c) = ((word >> 6) & 0x7, (word >> 3) & 0x7, word & 0x7);
+
+    match op {
+        UmOp::CMOV => format!("r{} := r{} if r{}", a, b, c),
+        UmOp::SLOAD => format!("r{} := m[r{}][r{}]", a, b, c),
+        UmOp::SSTORE => format!("m[r{}][r{}] := r{}", a, b, c),
+        UmOp::ADD => format!("r{} := r{} + r{}", a, b, c),
+        UmOp::MUL => format!("r{} := r{} * r{}", a, b, c),
+        UmOp::DIV => format!("r{} := r{} / r{}", a, b, c),
+        UmOp::NAND => format!("r{} := r{} nand r{}", a, b, c),
+        UmOp::HALT => String::from("halt"),
+        UmOp::MAP => format!("r{} := map r{}", b, c),
+        UmOp::UNMAP => format!("unmap r{}", c),
+        UmOp::OUT => format!("out r{}", c),
+        UmOp::IN => format!("in r{}", c),
+        UmOp::LOADP => format!("goto m[r{}][r{}]", b, c),
+        UmOp::LV => format!("r{} := {}", (word >> 25) & 0x7, word & 0x1FFFFFF),
+    }
+}
+
+/*
+ * Writes one line per word. With `annotate` each line is prefixed with
+ * the word's address and hex encoding, which the assembler won't accept.
+ */
+pub fn write_listing<W: Write>(out: &mut W, program: &[UmWord], annotate: bool) -> io::Result<()> {
+    for (addr, &word) in program.iter().enumerate() {
+        if annotate {
+            write!(out, "{:>8}: {:08x}  ", addr, word)?;
+        }
+        writeln!(out, "{}", disassemble(word))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disassembler::{disassemble, write_listing};
+
+    #[test]
+    fn test_disassemble_three_reg() {
+        assert_eq!(disassemble(0x300000D3), "r3 := r2 + r3");
+        assert_eq!(disassemble(0x20000053), "m[r1][r2] := r3");
+        assert_eq!(disassemble(0xC0000015), "goto m[r2][r5]");
+        assert_eq!(disassemble(0x8000000E), "r1 := map r6");
+    }
+
+    #[test]
+    fn test_disassemble_load_value() {
+        assert_eq!(disassemble(0xD4000005), "r2 := 5");
+        assert_eq!(disassemble(0xD7FFFFFF), "r3 := 33554431");
+    }
+
+    #[test]
+    fn test_disassemble_invalid() {
+        assert_eq!(disassemble(0xF0000000), ".word 0xf0000000");
+        /* HALT with operand bits, and ADD with bits above rA set */
+        assert_eq!(disassemble(0x70000001), ".word 0x70000001");
+        assert_eq!(disassemble(0x30000200), ".word 0x30000200");
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut out = Vec::new();
+        write_listing(&mut out, &[0xD4000005, 0x70000000], false).unwrap();
+        assert_eq!(out, b"r2 := 5\nhalt\n");
+
+        let mut out = Vec::new();
+        write_listing(&mut out, &[0xD4000005, 0x70000000], true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "       0: d4000005  r2 := 5\n       1: 70000000  halt\n"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::memory::LimitExceeded;
+use crate::um::UmWord;
+use std::fmt;
+use std::io;
+
+/* Every way the machine can fail, per the UM spec */
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum FaultKind {
+    /* opcode 14 or 15 */
+    InvalidOpcode,
+    /* SLOAD, SSTORE or LOADP naming a segment that is not mapped */
+    UnmappedSegment {
+        segment: UmWord,
+    },
+    /* SLOAD or SSTORE past the end of a mapped segment */
+    OutOfBounds {
+        segment: UmWord,
+        offset: UmWord,
+        len: usize,
+    },
+    /* DIV with a zero divisor */
+    DivideByZero,
+    /* UNMAP of segment 0 */
+    UnmapZero,
+    /* UNMAP of a segment that is not mapped */
+    UnmapInactive {
+        segment: UmWord,
+    },
+    /* OUT of a value that does not fit in a byte */
+    InvalidOutput {
+        value: UmWord,
+    },
+    /* MAP asked for more memory than the machine's limits or the host allow */
+    OutOfMemory {
+        size: UmWord,
+        limit: LimitExceeded,
+    },
+    /* OUT or IN failed on the underlying I/O handle */
+    Io(io::ErrorKind),
+}
+
+/* A machine fault along with the state of the machine when it happened */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct UmFault {
+    pub kind: FaultKind,
+    /* address of the faulting instruction in segment 0 */
+    pub pc: usize,
+    pub instruction: UmWord,
+    /* raw opcode bits, since an invalid opcode has no `UmOperations` */
+    pub opcode: u32,
+    pub registers: [UmWord; 8],
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultKind::InvalidOpcode => write!(f, "invalid opcode"),
+            FaultKind::UnmappedSegment { segment } => {
+                write!(f, "no segment mapped at {}", segment)
+            }
+            FaultKind::OutOfBounds {
+                segment,
+                offset,
+                len,
+            } => write!(
+                f,
+                "offset {} out of bounds for segment {} of length {}",
+                offset, segment, len
+            ),
+            FaultKind::DivideByZero => write!(f, "division by zero"),
+            FaultKind::UnmapZero => write!(f, "cannot unmap segment 0"),
+            FaultKind::UnmapInactive { segment } => {
+                write!(f, "cannot unmap inactive segment {}", segment)
+            }
+            FaultKind::InvalidOutput { value } => {
+                write!(f, "output value {} is larger than 255", value)
+            }
+            FaultKind::OutOfMemory { size, limit } => {
+                write!(f, "cannot map a segment of {} words: {}", size, limit)
+            }
+            FaultKind::Io(kind) => write!(f, "I/O error: {}", kind),
+        }
+    }
+}
+
+impl fmt::Display for UmFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "machine fault at pc {}: {} (instruction 0x{:08x}, opcode {})",
+            self.pc, self.kind, self.instruction, self.opcode
+        )?;
+        for (i, reg) in self.registers.iter().enumerate() {
+            write!(f, "\n  r{} = 0x{:08x}", i, reg)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for UmFault {}
@@ -1,3 +1,22 @@
+pub mod fault;
+pub mod loader;
+pub mod machine;
 pub mod memory;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
 pub mod um;
-pub mod assembler;
\ No newline at end of file
+pub mod umio;
+pub mod assembler;
+pub mod debugger;
+pub mod disassembler;
+#[cfg(test)]
+mod test_util;
+
+/* the stable API: build a `Machine` with `UmBuilder` and run it */
+pub use fault::{FaultKind, UmFault};
+pub use loader::LoadError;
+pub use machine::{Machine, MachineError, UmBuilder};
+pub use memory::{MemoryLimits, MemoryStats};
+pub use um::{HaltReason, StepResult, UmWord};
+pub use umio::{MemoryIo, ScriptedIo, StdIo, StreamIo, UmIo};
@@ -0,0 +1,407 @@
+use crate::um::UmWord;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/*
+ * Program images come in two formats. A raw image is nothing but
+ * big-endian 32-bit words. A container, all big-endian u32 fields, is
+ *
+ *   magic     F5 'U' 'M' 'I', an invalid instruction if run as raw words
+ *   version   FORMAT_VERSION
+ *   entry     pc to start at
+ *   words     number of code words
+ *   sections  number of sections after the code
+ *   checksum  CRC-32 of every byte after the header
+ *   code      `words` words
+ *   sections  each a kind, a length in bytes and that many bytes
+ *
+ * Sections of an unknown kind are skipped, so newer images still load.
+ */
+pub const MAGIC: [u8; 4] = [0xF5, b'U', b'M', b'I'];
+pub const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 24;
+
+/* a value and a u32-length-prefixed name per symbol */
+const SECTION_SYMBOLS: u32 = 1;
+/* a pc and the source line it came from per entry */
+const SECTION_LINES: u32 = 2;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ImageFormat {
+    Raw,
+    Container,
+}
+
+/* A named address, such as an assembler label */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: UmWord,
+}
+
+/* Maps an instruction back to the line of source it was assembled from */
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct LineInfo {
+    pub pc: UmWord,
+    pub line: u32,
+}
+
+/* A loaded program; raw images have no metadata and start at 0 */
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Image {
+    pub words: Vec<UmWord>,
+    pub entry: UmWord,
+    pub symbols: Vec<Symbol>,
+    pub lines: Vec<LineInfo>,
+}
+
+impl From<Vec<UmWord>> for Image {
+    fn from(words: Vec<UmWord>) -> Self {
+        Image {
+            words,
+            ..Image::default()
+        }
+    }
+}
+
+/* Why a program image could not be loaded */
+#[derive(Debug)]
+pub enum LoadError {
+    /* opening or reading the program failed */
+    Io(io::Error),
+    /* the image holds no words at all */
+    Empty,
+    /* the image ends partway through a word */
+    TrailingBytes { len: usize },
+    /* a container of a version this build does not read: 0, or newer */
+    UnsupportedVersion { version: u32 },
+    /* a container shorter than its header says */
+    Truncated,
+    /* a container whose contents do not match its checksum */
+    BadChecksum { expected: u32, actual: u32 },
+    /* a container whose entry point is outside its code */
+    BadEntry { entry: UmWord, words: usize },
+    /* a symbol or line section that does not parse */
+    BadSection { kind: u32 },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "could not read program: {}", e),
+            LoadError::Empty => write!(f, "program is empty"),
+            LoadError::TrailingBytes { len } => write!(
+                f,
+                "program is {} bytes long, not a whole number of 32-bit words",
+                len
+            ),
+            LoadError::UnsupportedVersion { version } => write!(
+                f,
+                "program image version {} is not supported, only 1 to {}",
+                version, FORMAT_VERSION
+            ),
+            LoadError::Truncated => write!(f, "program image is truncated"),
+            LoadError::BadChecksum { expected, actual } => write!(
+                f,
+                "program image is corrupt: checksum 0x{:08x}, expected 0x{:08x}",
+                actual, expected
+            ),
+            LoadError::BadEntry { entry, words } => write!(
+                f,
+                "entry point {} is outside the {} word program",
+                entry, words
+            ),
+            LoadError::BadSection { kind } => {
+                write!(f, "program image has a malformed section of kind {}", kind)
+            }
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+/* reads the code of a raw image or container */
+pub fn read_program<R: Read>(reader: R) -> Result<Vec<UmWord>, LoadError> {
+    Ok(read_image(reader)?.words)
+}
+
+/* reads a raw image or container, telling them apart by the magic */
+pub fn read_image<R: Read>(mut reader: R) -> Result<Image, LoadError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    if bytes.starts_with(&MAGIC) {
+        decode_container(&bytes)
+    } else {
+        Ok(decode_words(&bytes)?.into())
+    }
+}
+
+pub fn decode_words(bytes: &[u8]) -> Result<Vec<UmWord>, LoadError> {
+    if bytes.is_empty() {
+        return Err(LoadError::Empty);
+    }
+    if !bytes.len().is_multiple_of(4) {
+        return Err(LoadError::TrailingBytes { len: bytes.len() });
+    }
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|word| UmWord::from_be_bytes([word[0], word[1], word[2], word[3]]))
+        .collect())
+}
+
+/* walks a container, failing with `Truncated` if it runs out */
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if len > self.bytes.len() {
+            return Err(LoadError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn word(&mut self) -> Result<u32, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn decode_container(bytes: &[u8]) -> Result<Image, LoadError> {
+    let mut reader = Reader { bytes };
+    reader.take(MAGIC.len())?;
+    let version = reader.word()?;
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion { version });
+    }
+    let entry = reader.word()?;
+    let count = reader.word()? as usize;
+    let sections = reader.word()?;
+    let expected = reader.word()?;
+    let actual = crc32(reader.bytes);
+    if actual != expected {
+        return Err(LoadError::BadChecksum { expected, actual });
+    }
+
+    let code = reader.take(count.checked_mul(4).ok_or(LoadError::Truncated)?)?;
+    let mut image = Image::from(decode_words(code)?);
+    if entry as usize >= count {
+        return Err(LoadError::BadEntry {
+            entry,
+            words: count,
+        });
+    }
+    image.entry = entry;
+
+    for _ in 0..sections {
+        let kind = reader.word()?;
+        let len = reader.word()? as usize;
+        let mut section = Reader {
+            bytes: reader.take(len)?,
+        };
+        let bad = |_: LoadError| LoadError::BadSection { kind };
+        match kind {
+            SECTION_SYMBOLS => {
+                while !section.bytes.is_empty() {
+                    let value = section.word().map_err(bad)?;
+                    let len = section.word().map_err(bad)? as usize;
+                    let name = section.take(len).map_err(bad)?;
+                    let name = String::from_utf8(name.to_vec())
+                        .map_err(|_| LoadError::BadSection { kind })?;
+                    image.symbols.push(Symbol { name, value });
+                }
+            }
+            SECTION_LINES => {
+                while !section.bytes.is_empty() {
+                    let pc = section.word().map_err(bad)?;
+                    let line = section.word().map_err(bad)?;
+                    image.lines.push(LineInfo { pc, line });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(image)
+}
+
+/* writes `image` out, dropping everything but the code for a raw image */
+pub fn write_image<W: Write>(out: &mut W, image: &Image, format: ImageFormat) -> io::Result<()> {
+    let mut body = Vec::with_capacity(image.words.len() * 4);
+    for word in image.words.iter() {
+        body.extend_from_slice(&word.to_be_bytes());
+    }
+    if format == ImageFormat::Raw {
+        return out.write_all(&body);
+    }
+
+    let mut sections = 0u32;
+    if !image.symbols.is_empty() {
+        let mut section = Vec::new();
+        for symbol in image.symbols.iter() {
+            section.extend_from_slice(&symbol.value.to_be_bytes());
+            section.extend_from_slice(&(symbol.name.len() as u32).to_be_bytes());
+            section.extend_from_slice(symbol.name.as_bytes());
+        }
+        push_section(&mut body, SECTION_SYMBOLS, &section);
+        sections += 1;
+    }
+    if !image.lines.is_empty() {
+        let mut section = Vec::new();
+        for info in image.lines.iter() {
+            section.extend_from_slice(&info.pc.to_be_bytes());
+            section.extend_from_slice(&info.line.to_be_bytes());
+        }
+        push_section(&mut body, SECTION_LINES, &section);
+        sections += 1;
+    }
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(&MAGIC);
+    for field in [
+        FORMAT_VERSION,
+        image.entry,
+        image.words.len() as u32,
+        sections,
+        crc32(&body),
+    ] {
+        header.extend_from_slice(&field.to_be_bytes());
+    }
+    out.write_all(&header)?;
+    out.write_all(&body)
+}
+
+pub(crate) fn push_section(body: &mut Vec<u8>, kind: u32, section: &[u8]) {
+    body.extend_from_slice(&kind.to_be_bytes());
+    body.extend_from_slice(&(section.len() as u32).to_be_bytes());
+    body.extend_from_slice(section);
+}
+
+/* CRC-32 as used by zip and PNG */
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::{
+        read_image, read_program, write_image, Image, ImageFormat, LineInfo, LoadError, Symbol,
+    };
+    use std::io;
+
+    /* a reader that fails, standing in for a broken pipe or bad disk */
+    struct Broken;
+
+    impl io::Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+    }
+
+    #[test]
+    fn test_read_program() {
+        let bytes: &[u8] = &[0x70, 0, 0, 0, 0xD2, 0, 0, 0x2A];
+        assert_eq!(read_program(bytes).unwrap(), vec![0x70000000, 0xD200002A]);
+    }
+
+    #[test]
+    fn test_read_program_errors() {
+        assert!(matches!(read_program(&[][..]), Err(LoadError::Empty)));
+        assert!(matches!(
+            read_program(&[0x70, 0, 0, 0, 1][..]),
+            Err(LoadError::TrailingBytes { len: 5 })
+        ));
+        assert!(matches!(read_program(Broken), Err(LoadError::Io(_))));
+    }
+
+    fn sample() -> Image {
+        Image {
+            words: vec![0xD200002A, 0x70000000],
+            entry: 1,
+            symbols: vec![Symbol {
+                name: String::from("done"),
+                value: 1,
+            }],
+            lines: vec![LineInfo { pc: 0, line: 3 }, LineInfo { pc: 1, line: 5 }],
+        }
+    }
+
+    fn container(image: &Image) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_image(&mut bytes, image, ImageFormat::Container).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_container_round_trip() {
+        let image = sample();
+        assert_eq!(read_image(&container(&image)[..]).unwrap(), image);
+
+        /* a raw image keeps only the code */
+        let mut raw = Vec::new();
+        write_image(&mut raw, &image, ImageFormat::Raw).unwrap();
+        assert_eq!(raw, [0xD2, 0, 0, 0x2A, 0x70, 0, 0, 0]);
+        assert_eq!(read_image(&raw[..]).unwrap(), Image::from(image.words));
+    }
+
+    #[test]
+    fn test_container_errors() {
+        let bytes = container(&sample());
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            read_image(&corrupt[..]),
+            Err(LoadError::BadChecksum { .. })
+        ));
+
+        assert!(matches!(
+            read_image(&bytes[..10]),
+            Err(LoadError::Truncated)
+        ));
+
+        let mut unsupported = bytes.clone();
+        unsupported[7] = 2;
+        assert!(matches!(
+            read_image(&unsupported[..]),
+            Err(LoadError::UnsupportedVersion { version: 2 })
+        ));
+        unsupported[7] = 0;
+        assert!(matches!(
+            read_image(&unsupported[..]),
+            Err(LoadError::UnsupportedVersion { version: 0 })
+        ));
+
+        let mut image = sample();
+        image.entry = 2;
+        assert!(matches!(
+            read_image(&container(&image)[..]),
+            Err(LoadError::BadEntry { entry: 2, words: 2 })
+        ));
+    }
+}
@@ -0,0 +1,323 @@
+use crate::fault::UmFault;
+use crate::loader::LoadError;
+use crate::memory::{MemoryLimits, MemoryStats};
+use crate::snapshot::Snapshot;
+use crate::trace::{NoTrace, Tracer};
+use crate::um::{HaltReason, StepResult, UmWord, UM};
+use crate::umio::{StdIo, UmIo};
+use std::fmt;
+use std::fs::File;
+use std::path::PathBuf;
+use std::thread;
+
+/*
+ * The library's front door. `UmBuilder` sets a machine up and `Machine`
+ * runs it, so callers never need to touch `UM`'s fields:
+ *
+ *   let mut machine = UmBuilder::new()
+ *       .program_file("hello.um")
+ *       .io(MemoryIo::default())
+ *       .fuel(1_000_000)
+ *       .memory_limits(MemoryLimits {
+ *           max_words: Some(1 << 24),
+ *           ..MemoryLimits::default()
+ *       })
+ *       .build()?;
+ *   machine.run()?;
+ */
+pub struct UmBuilder<IO: UmIo = StdIo, T: Tracer = NoTrace> {
+    program: Option<Program>,
+    io: IO,
+    tracer: T,
+    limits: MemoryLimits,
+    fuel: Option<u64>,
+}
+
+enum Program {
+    Words(Vec<UmWord>),
+    Bytes(Vec<u8>),
+    File(PathBuf),
+    Snapshot(Snapshot),
+}
+
+/* Why `UmBuilder::build` or `Machine::run` gave up */
+#[derive(Debug)]
+pub enum MachineError {
+    /* the program or snapshot could not be loaded */
+    Load(LoadError),
+    /* `build` was called without a program */
+    NoProgram,
+    /* the program did something the UM spec forbids */
+    Fault(UmFault),
+    /* the instruction budget from `UmBuilder::fuel` ran out */
+    OutOfFuel,
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::Load(e) => write!(f, "{}", e),
+            MachineError::NoProgram => write!(f, "no program was given"),
+            MachineError::Fault(fault) => write!(f, "{}", fault),
+            MachineError::OutOfFuel => write!(f, "instruction budget exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for MachineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MachineError::Load(e) => Some(e),
+            MachineError::Fault(fault) => Some(fault),
+            _ => None,
+        }
+    }
+}
+
+impl From<LoadError> for MachineError {
+    fn from(e: LoadError) -> Self {
+        MachineError::Load(e)
+    }
+}
+
+impl From<UmFault> for MachineError {
+    fn from(fault: UmFault) -> Self {
+        MachineError::Fault(fault)
+    }
+}
+
+impl UmBuilder {
+    /* a machine on stdin and stdout, without tracing or an instruction budget */
+    pub fn new() -> Self {
+        Self {
+            program: None,
+            io: StdIo::new(),
+            tracer: NoTrace,
+            limits: MemoryLimits::default(),
+            fuel: None,
+        }
+    }
+}
+
+impl Default for UmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<IO: UmIo, T: Tracer> UmBuilder<IO, T> {
+    pub fn program_words(mut self, words: Vec<UmWord>) -> Self {
+        self.program = Some(Program::Words(words));
+        self
+    }
+
+    /* a raw image or container, as it would be on disk */
+    pub fn program_bytes(mut self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.program = Some(Program::Bytes(bytes.into()));
+        self
+    }
+
+    pub fn program_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.program = Some(Program::File(path.into()));
+        self
+    }
+
+    /* carry on from a snapshot instead of starting a program */
+    pub fn snapshot(mut self, snapshot: Snapshot) -> Self {
+        self.program = Some(Program::Snapshot(snapshot));
+        self
+    }
+
+    pub fn io<IO2: UmIo>(self, io: IO2) -> UmBuilder<IO2, T> {
+        UmBuilder {
+            program: self.program,
+            io,
+            tracer: self.tracer,
+            limits: self.limits,
+            fuel: self.fuel,
+        }
+    }
+
+    pub fn tracer<T2: Tracer>(self, tracer: T2) -> UmBuilder<IO, T2> {
+        UmBuilder {
+            program: self.program,
+            io: self.io,
+            tracer,
+            limits: self.limits,
+            fuel: self.fuel,
+        }
+    }
+
+    /* caps on what the program may MAP; unlimited by default */
+    pub fn memory_limits(mut self, limits: MemoryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /* the most instructions `Machine::run` and `step` will execute in total */
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn build(self) -> Result<Machine<IO, T>, MachineError> {
+        let mut um = UM::with_io(self.io).with_tracer(self.tracer);
+        um.memory.set_limits(self.limits);
+        match self.program.ok_or(MachineError::NoProgram)? {
+            Program::Words(words) => um.load_words(words),
+            Program::Bytes(bytes) => um.load_program(&bytes[..])?,
+            Program::File(path) => um.load_program(File::open(path).map_err(LoadError::from)?)?,
+            Program::Snapshot(snapshot) => um.restore(snapshot),
+        }
+
+        Ok(Machine {
+            um,
+            fuel: self.fuel,
+            stopped: None,
+        })
+    }
+}
+
+/* A loaded machine, built by `UmBuilder` */
+pub struct Machine<IO: UmIo = StdIo, T: Tracer = NoTrace> {
+    um: UM<IO, T>,
+    /* instructions left, if there is a budget */
+    fuel: Option<u64>,
+    /* the halt or fault the program stopped with, returned from then on */
+    stopped: Option<HaltReason>,
+}
+
+impl<IO: UmIo, T: Tracer> Machine<IO, T> {
+    /*
+     * runs until the program halts, faults or runs out of fuel, waiting
+     * for input whenever the I/O handle has none. A fault comes back as an
+     * error, so the reason is Halted or PcOutOfBounds.
+     */
+    pub fn run(&mut self) -> Result<HaltReason, MachineError> {
+        if self.fuel.is_none() && self.stopped.is_none() {
+            self.stopped = Some(self.um.run());
+        }
+        loop {
+            match self.step() {
+                StepResult::Running => {}
+                StepResult::Halted(HaltReason::Fault(fault)) => {
+                    return Err(MachineError::Fault(fault))
+                }
+                StepResult::Halted(reason) => return Ok(reason),
+                StepResult::WaitingForInput => thread::yield_now(),
+                StepResult::OutOfFuel => return Err(MachineError::OutOfFuel),
+            }
+        }
+    }
+
+    /*
+     * executes one instruction, or reports `OutOfFuel` without executing.
+     * Once the program has halted or faulted, that is all it reports.
+     */
+    pub fn step(&mut self) -> StepResult {
+        if let Some(reason) = &self.stopped {
+            return StepResult::Halted(reason.clone());
+        }
+        if self.fuel == Some(0) {
+            return StepResult::OutOfFuel;
+        }
+        let result = self.um.step();
+        match result {
+            /* an IN still waiting for input did not run, so it costs nothing */
+            StepResult::WaitingForInput => return result,
+            StepResult::Halted(ref reason) => self.stopped = Some(reason.clone()),
+            _ => {}
+        }
+        if let Some(fuel) = &mut self.fuel {
+            *fuel -= 1;
+        }
+        result
+    }
+
+    /* instructions left before `OutOfFuel`, or None without a budget */
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn registers(&self) -> [UmWord; 8] {
+        self.um.registers
+    }
+
+    pub fn pc(&self) -> usize {
+        self.um.pc
+    }
+
+    /* None if the segment is unmapped or the offset is past its end */
+    pub fn read_word(&self, segment: UmWord, offset: UmWord) -> Option<UmWord> {
+        self.um
+            .memory
+            .segments
+            .get(segment as usize)?
+            .as_ref()?
+            .get(offset as usize)
+            .copied()
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.um.memory.stats()
+    }
+
+    pub fn io(&self) -> &IO {
+        &self.um.io
+    }
+
+    pub fn io_mut(&mut self) -> &mut IO {
+        &mut self.um.io
+    }
+
+    pub fn tracer(&self) -> &T {
+        &self.um.tracer
+    }
+
+    pub fn tracer_mut(&mut self) -> &mut T {
+        &mut self.um.tracer
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.um.snapshot()
+    }
+
+    pub fn memory_limits(&self) -> MemoryLimits {
+        self.um.memory.limits()
+    }
+
+    /* the underlying machine, for tools like the debugger */
+    pub fn into_inner(self) -> UM<IO, T> {
+        self.um
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::UmBuilder;
+    use crate::test_util::{three_reg, Pipe};
+    use crate::um::{HaltReason, StepResult, UmOperations};
+
+    #[test]
+    fn test_fuel_waiting_for_input() {
+        let mut machine = UmBuilder::new()
+            .program_words(vec![
+                three_reg(UmOperations::IN, 0, 0, 1),
+                three_reg(UmOperations::OUT, 0, 0, 1),
+                three_reg(UmOperations::HALT, 0, 0, 0),
+            ])
+            .io(Pipe::default())
+            .fuel(3)
+            .build()
+            .unwrap();
+        for _ in 0..5 {
+            assert_eq!(machine.step(), StepResult::WaitingForInput);
+        }
+        assert_eq!(machine.fuel(), Some(3));
+
+        machine.io_mut().input = Some(b'x');
+        assert_eq!(machine.run().unwrap(), HaltReason::Halted);
+        assert_eq!(machine.fuel(), Some(0));
+        assert_eq!(machine.io().output, b"x");
+    }
+}
@@ -1,19 +1,342 @@
-mod memory;
-mod um;
-
 use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
 use std::process;
+use std::thread;
+use std::time::Instant;
+use um::fault::FaultKind;
+use um::memory::MemoryLimits;
+use um::profile::Profiler;
+use um::snapshot::Snapshot;
+use um::trace::{TraceFilter, TraceFormat, TraceWriter, Tracer};
+use um::um::{HaltReason, StepResult, UmOperations, UM};
+use um::umio::{StreamIo, UmIo};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+const USAGE: &str = "\
+Usage: um [options] <program.um>
+       um [options] --resume <snapshot>
+The program is read from stdin if it is `-`.
+  --stdin-file <file>       give the program <file> as its input
+  --output-file <file>      write the program's output to <file>
+  --max-instructions <n>    stop after executing <n> instructions
+  --max-memory <words>      fault when the program maps more than <words>,
+                            268435456 (1 GiB) by default, or `unlimited`
+  --trace <file>            log every instruction executed to <file>
+  --trace-format <fmt>      json (default) or binary
+  --trace-pc <lo>..<hi>     only log instructions with lo <= pc < hi
+  --trace-op <op,...>       only log these opcodes, e.g. sload,sstore
+  --profile                 print instruction counts and hot spots to stderr
+  --stats                   print memory use to stderr when the machine stops
+  --time                    print the running time to stderr
+  --save-on-halt <file>     snapshot the machine to <file> when it halts
+  --resume <file>           carry on from a snapshot instead of a program
+Exit status:
+    0  the program executed HALT
+    1  bad arguments, or the program or a file could not be opened
+    2  the program ran off the end of segment 0
+   10  invalid opcode
+   11  unmapped segment
+   12  segment offset out of bounds
+   13  division by zero
+   14  bad UNMAP
+   15  output value over 255
+   16  I/O error on the program's input or output
+   20  --max-memory exceeded, or the host is out of memory
+   21  --max-instructions exceeded";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_PC_OUT_OF_BOUNDS: i32 = 2;
+const EXIT_OUT_OF_MEMORY: i32 = 20;
+const EXIT_OUT_OF_FUEL: i32 = 21;
+
+/* words the program may map without --max-memory */
+const DEFAULT_MAX_MEMORY: usize = 1 << 28;
+
+/* hottest pcs shown by --profile */
+const PROFILE_TOP: usize = 20;
+
+/* the program's input and output, picked on the command line */
+type Io = StreamIo<Box<dyn Read>, Box<dyn Write>>;
+
+struct Options {
+    program: Option<String>,
+    stdin_file: Option<String>,
+    output_file: Option<String>,
+    max_instructions: Option<u64>,
+    max_memory: Option<usize>,
+    trace: Option<String>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    profile: bool,
+    stats: bool,
+    time: bool,
+    save_on_halt: Option<String>,
+    resume: Option<String>,
+}
+
+/* how the machine stopped */
+enum Outcome {
+    Stopped(HaltReason),
+    OutOfFuel,
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("um: {}\n{}", problem, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+/* a file that could not be opened or written */
+fn file_error(path: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path, e);
+    process::exit(EXIT_USAGE);
+}
+
+fn exit_code(kind: FaultKind) -> i32 {
+    match kind {
+        FaultKind::InvalidOpcode => 10,
+        FaultKind::UnmappedSegment { .. } => 11,
+        FaultKind::OutOfBounds { .. } => 12,
+        FaultKind::DivideByZero => 13,
+        FaultKind::UnmapZero | FaultKind::UnmapInactive { .. } => 14,
+        FaultKind::InvalidOutput { .. } => 15,
+        FaultKind::Io(_) => 16,
+        FaultKind::OutOfMemory { .. } => EXIT_OUT_OF_MEMORY,
+    }
+}
+
+fn parse_op(name: &str) -> Option<UmOperations> {
+    (0..14)
+        .filter_map(|opcode| UmOperations::decode(opcode << 28))
+        .find(|op| format!("{:?}", op).eq_ignore_ascii_case(name))
+}
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <program.um>", args[0]);
-        process::exit(1);
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        program: None,
+        stdin_file: None,
+        output_file: None,
+        max_instructions: None,
+        max_memory: Some(DEFAULT_MAX_MEMORY),
+        trace: None,
+        format: TraceFormat::Json,
+        filter: TraceFilter::default(),
+        profile: false,
+        stats: false,
+        time: false,
+        save_on_halt: None,
+        resume: None,
+    };
+    let mut program = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || match args.next() {
+            Some(value) => value.as_str(),
+            None => usage(&format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--stdin-file" => options.stdin_file = Some(value().to_string()),
+            "--output-file" => options.output_file = Some(value().to_string()),
+            "--max-instructions" => match value().parse() {
+                Ok(n) => options.max_instructions = Some(n),
+                Err(_) => usage(&format!("bad instruction count for {}", arg)),
+            },
+            "--max-memory" => match value() {
+                "unlimited" => options.max_memory = None,
+                words => match words.parse() {
+                    Ok(n) => options.max_memory = Some(n),
+                    Err(_) => usage(&format!("bad word count for {}", arg)),
+                },
+            },
+            "--trace" => options.trace = Some(value().to_string()),
+            "--trace-format" => {
+                options.format = match value() {
+                    "json" => TraceFormat::Json,
+                    "binary" => TraceFormat::Binary,
+                    other => usage(&format!("unknown trace format `{}`", other)),
+                }
+            }
+            "--trace-pc" => {
+                let range = value();
+                let pcs = range
+                    .split_once("..")
+                    .and_then(|(lo, hi)| Some(lo.parse().ok()?..hi.parse().ok()?));
+                match pcs {
+                    Some(pcs) => options.filter.pcs = Some(pcs),
+                    None => usage(&format!("bad pc range `{}`", range)),
+                }
+            }
+            "--trace-op" => {
+                let mut ops = Vec::new();
+                for name in value().split(',') {
+                    match parse_op(name) {
+                        Some(op) => ops.push(op),
+                        None => usage(&format!("unknown opcode `{}`", name)),
+                    }
+                }
+                options.filter.ops = Some(ops);
+            }
+            "--profile" => options.profile = true,
+            "--stats" => options.stats = true,
+            "--time" => options.time = true,
+            "--save-on-halt" => options.save_on_halt = Some(value().to_string()),
+            "--resume" => options.resume = Some(value().to_string()),
+            _ if arg.starts_with("--") => usage(&format!("unknown option `{}`", arg)),
+            _ if program.is_none() => program = Some(arg.clone()),
+            _ => usage("only one program can be run"),
+        }
     }
 
-    let mut machine = um::UM::new();
-    machine.init_program(&args[1]);
-    machine.run();
+    match (&program, &options.resume) {
+        (None, None) => usage("no program given"),
+        (Some(_), Some(_)) => usage("a program cannot be given with --resume"),
+        _ => options.program = program,
+    }
+    options
+}
+
+fn open_io(options: &Options) -> Io {
+    let input: Box<dyn Read> = match &options.stdin_file {
+        Some(path) => Box::new(File::open(path).unwrap_or_else(|e| file_error(path, e))),
+        None => Box::new(io::stdin()),
+    };
+    let output: Box<dyn Write> = match &options.output_file {
+        Some(path) => Box::new(File::create(path).unwrap_or_else(|e| file_error(path, e))),
+        None => Box::new(io::stdout()),
+    };
+    StreamIo::new(input, output)
+}
+
+fn run<T: Tracer>(machine: &mut UM<Io, T>, fuel: Option<u64>) -> Outcome {
+    let mut fuel = match fuel {
+        Some(fuel) => fuel,
+        None => {
+            return Outcome::Stopped(machine.run());
+        }
+    };
+    loop {
+        if fuel == 0 {
+            return Outcome::OutOfFuel;
+        }
+        fuel -= 1;
+        match machine.step() {
+            StepResult::Running => {}
+            StepResult::Halted(reason) => return Outcome::Stopped(reason),
+            /* the instruction did not run, so it costs nothing */
+            StepResult::WaitingForInput => {
+                fuel += 1;
+                thread::yield_now();
+            }
+            StepResult::OutOfFuel => return Outcome::OutOfFuel,
+        }
+    }
+}
+
+/*
+ * once the machine stops: prints --stats, and on a halt saves the snapshot
+ * for --save-on-halt, which carries on after the HALT when resumed
+ */
+fn finish<T: Tracer>(options: &Options, machine: &mut UM<Io, T>, outcome: &Outcome) {
+    /* a budget running out leaves output behind in the buffer */
+    let _ = machine.io.flush();
+    if options.stats {
+        eprintln!("{}", machine.memory.stats());
+    }
+    let path = match &options.save_on_halt {
+        Some(path) if matches!(outcome, Outcome::Stopped(HaltReason::Halted)) => path,
+        _ => return,
+    };
+    let saved = File::create(path).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        machine.snapshot().write(&mut out)?;
+        out.flush()
+    });
+    if let Err(e) = saved {
+        file_error(path, format!("could not save snapshot: {}", e));
+    }
 }
 
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_args(&args);
+
+    let mut machine = UM::with_io(open_io(&options));
+    match (&options.program, &options.resume) {
+        (Some(path), _) if path == "-" => {
+            if let Err(e) = machine.load_program(io::stdin().lock()) {
+                file_error("<stdin>", e);
+            }
+        }
+        (Some(path), _) => {
+            if let Err(e) = machine.init_program(path) {
+                file_error(path, e);
+            }
+        }
+        (_, Some(path)) => {
+            let snapshot = File::open(path)
+                .map_err(Into::into)
+                .and_then(Snapshot::read)
+                .unwrap_or_else(|e| file_error(path, e));
+            machine.restore(snapshot);
+        }
+        (None, None) => unreachable!(),
+    }
+    machine.memory.set_limits(MemoryLimits {
+        max_words: options.max_memory,
+        ..MemoryLimits::default()
+    });
+
+    let tracer = options.trace.as_ref().map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| file_error(path, e));
+        let mut tracer = TraceWriter::new(BufWriter::new(file), options.format);
+        tracer.filter = options.filter.clone();
+        tracer
+    });
+
+    let start = Instant::now();
+    let outcome = if tracer.is_none() && !options.profile {
+        let outcome = run(&mut machine, options.max_instructions);
+        finish(&options, &mut machine, &outcome);
+        outcome
+    } else {
+        let profiler = options.profile.then(Profiler::new);
+        let mut machine = machine.with_tracer((tracer, profiler));
+        let outcome = run(&mut machine, options.max_instructions);
+        finish(&options, &mut machine, &outcome);
+        let (tracer, profiler) = &mut machine.tracer;
+        if let Some(tracer) = tracer {
+            if let Err(e) = tracer.finish() {
+                eprintln!("{}: {}", options.trace.as_deref().unwrap_or_default(), e);
+            }
+        }
+        if let Some(profiler) = profiler {
+            eprint!("{}", profiler.report(machine.memory.stats(), PROFILE_TOP));
+        }
+        outcome
+    };
+    if options.time {
+        eprintln!("{:.3}s", start.elapsed().as_secs_f64());
+    }
+
+    match outcome {
+        Outcome::Stopped(HaltReason::Halted) => {}
+        Outcome::Stopped(reason @ HaltReason::PcOutOfBounds { .. }) => {
+            eprintln!("um: {}", reason);
+            process::exit(EXIT_PC_OUT_OF_BOUNDS);
+        }
+        Outcome::Stopped(HaltReason::Fault(fault)) => {
+            eprintln!("{}", fault);
+            process::exit(exit_code(fault.kind));
+        }
+        Outcome::OutOfFuel => {
+            eprintln!(
+                "um: stopped after {} instructions",
+                options.max_instructions.unwrap_or_default()
+            );
+            process::exit(EXIT_OUT_OF_FUEL);
+        }
+    }
+}
@@ -1,8 +1,93 @@
 use crate::um::UmWord;
+use std::fmt;
+use std::rc::Rc;
+
+/*
+ * Segments are shared copy-on-write, so LOADP can hand segment 0 the same
+ * words as the segment it loads. Write through `Rc::make_mut`.
+ */
+pub type Segment = Rc<[UmWord]>;
 
 pub struct Memory {
-    pub segments: Vec<Option<Vec<UmWord>>>,
+    pub segments: Vec<Option<Segment>>,
     pub free_list: Vec<usize>,
+    limits: MemoryLimits,
+    stats: MemoryStats,
+}
+
+/*
+ * Caps on what MAP may allocate, so a program cannot take the host down
+ * with it. Only segments mapped by the program count, not segment 0.
+ */
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct MemoryLimits {
+    /* words in all mapped segments together */
+    pub max_words: Option<usize>,
+    pub max_segments: Option<usize>,
+    /* words in any one segment */
+    pub max_segment_words: Option<usize>,
+}
+
+/*
+ * Which of the `MemoryLimits` a MAP would have broken, and its value, or
+ * `Host` when the host looked unable to allocate it
+ */
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum LimitExceeded {
+    Words(usize),
+    Segments(usize),
+    SegmentWords(usize),
+    Host,
+}
+
+/* segments bigger than this are probed with a fallible reservation first */
+const LARGE_SEGMENT_WORDS: usize = 1 << 20;
+
+/* MAP and UNMAP activity since the memory was created */
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub maps: u64,
+    pub unmaps: u64,
+    /* segments mapped by the program right now and at most, not counting 0 */
+    pub live_segments: usize,
+    pub peak_live_segments: usize,
+    /* words in those segments right now and at most */
+    pub live_words: usize,
+    pub peak_words: usize,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Words(max) => write!(f, "limit of {} mapped words", max),
+            LimitExceeded::Segments(max) => write!(f, "limit of {} mapped segments", max),
+            LimitExceeded::SegmentWords(max) => {
+                write!(f, "limit of {} words per segment", max)
+            }
+            LimitExceeded::Host => write!(f, "more memory than the host could allocate"),
+        }
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} maps, {} unmaps, {} live segments (peak {}), {} live words (peak {})",
+            self.maps,
+            self.unmaps,
+            self.live_segments,
+            self.peak_live_segments,
+            self.live_words,
+            self.peak_words
+        )
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
@@ -11,29 +96,150 @@ impl Memory {
             segments: vec![None],
             /* NEEDSWORK: should be pretty big, but change here for optimzation */
             free_list: Vec::with_capacity(1 << 16),
+            limits: MemoryLimits::default(),
+            stats: MemoryStats::default(),
+        }
+    }
+
+    /* memory as saved in a snapshot; MAP and UNMAP counts start over */
+    pub fn with_segments(segments: Vec<Option<Segment>>, free_list: Vec<usize>) -> Self {
+        let mapped = segments.iter().skip(1).flatten();
+        let live_segments = mapped.clone().count();
+        let live_words = mapped.map(|segment| segment.len()).sum();
+        Self {
+            segments,
+            free_list,
+            limits: MemoryLimits::default(),
+            stats: MemoryStats {
+                live_segments,
+                peak_live_segments: live_segments,
+                live_words,
+                peak_words: live_words,
+                ..MemoryStats::default()
+            },
         }
     }
 
-    pub fn map_segment(&mut self, size: usize) -> usize {
-        let segment = vec![0; size];
+    pub fn limits(&self) -> MemoryLimits {
+        self.limits
+    }
+
+    /* only checked by later MAPs; nothing already mapped is taken away */
+    pub fn set_limits(&mut self, limits: MemoryLimits) {
+        self.limits = limits;
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        self.stats
+    }
+
+    pub fn map_segment(&mut self, size: usize) -> Result<usize, LimitExceeded> {
+        let limits = self.limits;
+        match limits.max_segment_words {
+            Some(max) if size > max => return Err(LimitExceeded::SegmentWords(max)),
+            _ => {}
+        }
+        match limits.max_segments {
+            Some(max) if self.stats.live_segments >= max => {
+                return Err(LimitExceeded::Segments(max))
+            }
+            _ => {}
+        }
+        match limits.max_words {
+            Some(max) if size > max.saturating_sub(self.stats.live_words) => {
+                return Err(LimitExceeded::Words(max))
+            }
+            _ => {}
+        }
+
+        /*
+         * an allocation that fails aborts the process, and `Rc` has no
+         * stable fallible way to allocate a zeroed slice. As a heuristic, a
+         * large segment is first reserved with `try_reserve_exact`, which
+         * reports the failure, and released again. The real allocation
+         * below can still abort if memory runs out in between, or if the
+         * host overcommits and the probe succeeds anyway.
+         */
+        if size > LARGE_SEGMENT_WORDS {
+            let mut words: Vec<UmWord> = Vec::new();
+            words
+                .try_reserve_exact(size)
+                .map_err(|_| LimitExceeded::Host)?;
+        }
+        /* SAFETY: all zero bits is a valid u32 */
+        let segment: Segment = unsafe { Rc::new_zeroed_slice(size).assume_init() };
+        let stats = &mut self.stats;
+        stats.maps += 1;
+        stats.live_segments += 1;
+        stats.peak_live_segments = stats.peak_live_segments.max(stats.live_segments);
+        stats.live_words += size;
+        stats.peak_words = stats.peak_words.max(stats.live_words);
         /* if there is a free identifier, just use that */
         let idx = self.free_list.pop();
         match idx {
             Some(index) => {
                 self.segments[index] = Some(segment);
-                index
+                Ok(index)
             }
             None => {
                 /* add a new identifier */
                 self.segments.push(Some(segment));
-                self.segments.len() - 1
+                Ok(self.segments.len() - 1)
             }
         }
     }
 
     pub fn unmap_segment(&mut self, idx: usize) {
         /* we can just set to None because of ownership 🙏 */
-        self.segments[idx] = None;
+        if let Some(segment) = self.segments[idx].take() {
+            self.stats.live_words = self.stats.live_words.saturating_sub(segment.len());
+        }
         self.free_list.push(idx);
+        self.stats.unmaps += 1;
+        self.stats.live_segments = self.stats.live_segments.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::{LimitExceeded, Memory, MemoryLimits};
+
+    #[test]
+    fn test_memory_stats() {
+        let mut memory = Memory::new();
+        let a = memory.map_segment(10).unwrap();
+        let b = memory.map_segment(5).unwrap();
+        memory.unmap_segment(a);
+        assert_eq!(memory.map_segment(1), Ok(a));
+        memory.unmap_segment(b);
+
+        let stats = memory.stats();
+        assert_eq!((stats.maps, stats.unmaps), (3, 2));
+        assert_eq!((stats.live_segments, stats.peak_live_segments), (1, 2));
+        assert_eq!((stats.live_words, stats.peak_words), (1, 15));
+    }
+
+    #[test]
+    fn test_memory_limits() {
+        let mut memory = Memory::new();
+        memory.set_limits(MemoryLimits {
+            max_words: Some(10),
+            max_segments: Some(2),
+            max_segment_words: Some(8),
+        });
+        assert_eq!(memory.map_segment(9), Err(LimitExceeded::SegmentWords(8)));
+        memory.map_segment(8).unwrap();
+        assert_eq!(memory.map_segment(3), Err(LimitExceeded::Words(10)));
+        memory.map_segment(2).unwrap();
+        assert_eq!(memory.map_segment(0), Err(LimitExceeded::Segments(2)));
+        assert_eq!(memory.stats().maps, 2);
+    }
+
+    #[test]
+    fn test_map_allocation_failure() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.map_segment(usize::MAX), Err(LimitExceeded::Host));
+        assert_eq!(memory.stats().maps, 0);
+        assert_eq!(memory.map_segment(1), Ok(1));
     }
 }
@@ -0,0 +1,182 @@
+use crate::disassembler::disassemble;
+use crate::memory::MemoryStats;
+use crate::trace::{TraceEvent, Tracer};
+use crate::um::{UmOperations, UmWord};
+use std::cmp::Reverse;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/* Counts what the machine executes; attach it with `UM::with_tracer` */
+#[derive(Default)]
+pub struct Profiler {
+    /* indexed by opcode */
+    ops: [u64; 14],
+    /* executions and the last word seen, indexed by pc */
+    pcs: Vec<(u64, UmWord)>,
+    started: Option<Instant>,
+}
+
+/* Where the time went, as of `Profiler::report` */
+#[derive(PartialEq, Debug, Clone)]
+pub struct ProfileReport {
+    pub instructions: u64,
+    /* every opcode that ran, most frequent first */
+    pub ops: Vec<(UmOperations, u64)>,
+    /* the hottest pcs, most frequent first */
+    pub hot: Vec<HotSpot>,
+    pub memory: MemoryStats,
+    /* from the first instruction to the report */
+    pub elapsed: Duration,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HotSpot {
+    pub pc: usize,
+    pub count: u64,
+    pub word: UmWord,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* `memory` is usually `machine.memory.stats()` */
+    pub fn report(&self, memory: MemoryStats, top: usize) -> ProfileReport {
+        let mut ops: Vec<(UmOperations, u64)> = (0..14u32)
+            .filter_map(|opcode| {
+                Some((
+                    UmOperations::decode(opcode << 28)?,
+                    self.ops[opcode as usize],
+                ))
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        ops.sort_by_key(|&(_, count)| Reverse(count));
+
+        let mut hot: Vec<HotSpot> = self
+            .pcs
+            .iter()
+            .enumerate()
+            .filter(|(_, &(count, _))| count > 0)
+            .map(|(pc, &(count, word))| HotSpot { pc, count, word })
+            .collect();
+        hot.sort_by(|a, b| b.count.cmp(&a.count).then(a.pc.cmp(&b.pc)));
+        hot.truncate(top);
+
+        ProfileReport {
+            instructions: self.ops.iter().sum(),
+            ops,
+            hot,
+            memory,
+            elapsed: self.started.map_or(Duration::ZERO, |start| start.elapsed()),
+        }
+    }
+}
+
+impl Tracer for Profiler {
+    fn trace(&mut self, event: &TraceEvent) {
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        self.ops[event.op as usize] += 1;
+        if event.pc >= self.pcs.len() {
+            self.pcs.resize(event.pc + 1, (0, 0));
+        }
+        let slot = &mut self.pcs[event.pc];
+        *slot = (slot.0 + 1, event.word);
+    }
+}
+
+impl ProfileReport {
+    /* millions of instructions per second */
+    pub fn mips(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.instructions as f64 / secs / 1e6,
+            _ => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let percent = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+
+        writeln!(
+            f,
+            "{} instructions in {:.3}s ({:.1} MIPS)",
+            self.instructions,
+            self.elapsed.as_secs_f64(),
+            self.mips()
+        )?;
+        writeln!(f, "{}", self.memory)?;
+
+        writeln!(f, "\nopcodes:")?;
+        for (op, count) in self.ops.iter() {
+            writeln!(
+                f,
+                "  {:<6} {:>14} {:>6.2}%",
+                format!("{:?}", op),
+                count,
+                percent(*count)
+            )?;
+        }
+
+        writeln!(f, "\nhottest pcs:")?;
+        for spot in self.hot.iter() {
+            writeln!(
+                f,
+                "  {:>8}: {:>14} {:>6.2}%  {}",
+                spot.pc,
+                spot.count,
+                percent(spot.count),
+                disassemble(spot.word)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::UMAssembler;
+    use crate::profile::Profiler;
+    use crate::um::{HaltReason, UmOperations, UM};
+    use crate::umio::MemoryIo;
+
+    /* maps and unmaps a segment three times */
+    const SOURCE: &str = "
+        r1 := 3
+        r4 := 1
+        r6 := loop
+        loop: r7 := map r4
+        unmap r7
+        r2 := r0 nand r0
+        r1 := r1 + r2
+        r3 := done
+        r3 := r6 if r1
+        goto m[r0][r3]
+        done: halt
+    ";
+
+    #[test]
+    fn test_profile_report() {
+        let program = UMAssembler {}
+            .assemble("test.ums", SOURCE.as_bytes())
+            .unwrap();
+        let mut machine = UM::with_io(MemoryIo::default()).with_tracer(Profiler::new());
+        machine.load_words(program);
+        assert_eq!(machine.run(), HaltReason::Halted);
+
+        let report = machine.tracer.report(machine.memory.stats(), 2);
+        assert_eq!(report.instructions, 3 + 3 * 7 + 1);
+        assert_eq!(report.ops[0], (UmOperations::LV, 3 + 3));
+        assert_eq!(report.hot.len(), 2);
+        assert_eq!((report.hot[0].pc, report.hot[0].count), (3, 3));
+        assert_eq!(report.memory.maps, 3);
+        assert_eq!(report.memory.unmaps, 3);
+        assert_eq!(report.memory.peak_live_segments, 1);
+        assert!(report.to_string().contains("r7 := map r4"));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::loader::{crc32, push_section, Reader};
+use crate::memory::Segment;
+use crate::um::UmWord;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/*
+ * The state of a machine, enough to carry on where it left off. On disk,
+ * all big-endian u32 fields, it is
+ *
+ *   magic     F5 'U' 'M' 'S'
+ *   version   SNAPSHOT_VERSION
+ *   checksum  CRC-32 of every byte after the header
+ *   sections  each a kind, a length in bytes and that many bytes
+ *
+ * with one machine section (pc, the eight registers and the number of
+ * segment identifiers), a segment section (identifier then words) per
+ * mapped segment, and one free list section. Unknown sections are skipped.
+ * I/O state, such as input already buffered, is not part of a snapshot.
+ */
+pub const SNAPSHOT_MAGIC: [u8; 4] = [0xF5, b'U', b'M', b'S'];
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const SECTION_MACHINE: u32 = 1;
+const SECTION_SEGMENT: u32 = 2;
+const SECTION_FREE_LIST: u32 = 3;
+
+/* Why a snapshot could not be read */
+#[derive(Debug)]
+pub enum SnapshotError {
+    /* opening or reading the snapshot failed */
+    Io(io::Error),
+    /* the file is something else, such as a program */
+    NotASnapshot,
+    /* a snapshot of a version this build does not read: 0, or newer */
+    UnsupportedVersion { version: u32 },
+    /* a section shorter than it says */
+    Truncated,
+    /* a snapshot whose contents do not match its checksum */
+    BadChecksum { expected: u32, actual: u32 },
+    /* a snapshot whose segments and free list do not fit together */
+    Inconsistent { reason: &'static str },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "could not read snapshot: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "not a machine snapshot"),
+            SnapshotError::UnsupportedVersion { version } => write!(
+                f,
+                "snapshot version {} is not supported, only 1 to {}",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadChecksum { expected, actual } => write!(
+                f,
+                "snapshot is corrupt: checksum 0x{:08x}, expected 0x{:08x}",
+                actual, expected
+            ),
+            SnapshotError::Inconsistent { reason } => {
+                write!(f, "snapshot is inconsistent: {}", reason)
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Snapshot {
+    pub registers: [UmWord; 8],
+    pub pc: usize,
+    pub segments: Vec<Option<Segment>>,
+    pub free_list: Vec<usize>,
+}
+
+impl Snapshot {
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut body = Vec::new();
+
+        let mut machine = Vec::with_capacity(40);
+        machine.extend_from_slice(&(self.pc as u32).to_be_bytes());
+        for reg in self.registers.iter() {
+            machine.extend_from_slice(&reg.to_be_bytes());
+        }
+        machine.extend_from_slice(&(self.segments.len() as u32).to_be_bytes());
+        push_section(&mut body, SECTION_MACHINE, &machine);
+
+        for (id, segment) in self.segments.iter().enumerate() {
+            if let Some(words) = segment {
+                let mut section = Vec::with_capacity(4 + words.len() * 4);
+                section.extend_from_slice(&(id as u32).to_be_bytes());
+                for word in words.iter() {
+                    section.extend_from_slice(&word.to_be_bytes());
+                }
+                push_section(&mut body, SECTION_SEGMENT, &section);
+            }
+        }
+
+        let mut free = Vec::with_capacity(self.free_list.len() * 4);
+        for &id in self.free_list.iter() {
+            free.extend_from_slice(&(id as u32).to_be_bytes());
+        }
+        push_section(&mut body, SECTION_FREE_LIST, &free);
+
+        out.write_all(&SNAPSHOT_MAGIC)?;
+        out.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+        out.write_all(&crc32(&body).to_be_bytes())?;
+        out.write_all(&body)
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Snapshot, SnapshotError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut reader = Reader { bytes: &bytes };
+        if reader.take(4).ok() != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = word(&mut reader)?;
+        if version == 0 || version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion { version });
+        }
+        let expected = word(&mut reader)?;
+        let actual = crc32(reader.bytes);
+        if actual != expected {
+            return Err(SnapshotError::BadChecksum { expected, actual });
+        }
+
+        let mut snapshot = None;
+        let mut free_list = Vec::new();
+        while !reader.bytes.is_empty() {
+            let kind = word(&mut reader)?;
+            let len = word(&mut reader)? as usize;
+            let mut section = Reader {
+                bytes: reader.take(len).map_err(|_| SnapshotError::Truncated)?,
+            };
+            match kind {
+                SECTION_MACHINE => {
+                    let pc = word(&mut section)? as usize;
+                    let mut registers = [0; 8];
+                    for reg in registers.iter_mut() {
+                        *reg = word(&mut section)?;
+                    }
+                    let slots = word(&mut section)? as usize;
+                    /*
+                     * each identifier is either a segment section or a free
+                     * list entry, so takes at least 4 bytes of the file
+                     */
+                    if slots > bytes.len() / 4 {
+                        return Err(SnapshotError::Inconsistent {
+                            reason: "more segment identifiers than the snapshot holds",
+                        });
+                    }
+                    snapshot = Some(Snapshot {
+                        registers,
+                        pc,
+                        segments: vec![None; slots],
+                        free_list: Vec::new(),
+                    });
+                }
+                SECTION_SEGMENT => {
+                    let snapshot = snapshot.as_mut().ok_or(SnapshotError::Inconsistent {
+                        reason: "segment before machine state",
+                    })?;
+                    let id = word(&mut section)? as usize;
+                    let words: Vec<UmWord> = section
+                        .bytes
+                        .chunks(4)
+                        .map(|w| w.try_into().map(UmWord::from_be_bytes))
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| SnapshotError::Truncated)?;
+                    match snapshot.segments.get_mut(id) {
+                        Some(slot @ None) => *slot = Some(words.into()),
+                        _ => {
+                            return Err(SnapshotError::Inconsistent {
+                                reason: "segment identifier out of range or repeated",
+                            })
+                        }
+                    }
+                }
+                SECTION_FREE_LIST => {
+                    while !section.bytes.is_empty() {
+                        free_list.push(word(&mut section)? as usize);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut snapshot = snapshot.ok_or(SnapshotError::Inconsistent {
+            reason: "no machine state",
+        })?;
+        if snapshot.segments.first().is_none_or(|s| s.is_none()) {
+            return Err(SnapshotError::Inconsistent {
+                reason: "segment 0 is not mapped",
+            });
+        }
+        let mut free = vec![false; snapshot.segments.len()];
+        for &id in free_list.iter() {
+            match snapshot.segments.get(id) {
+                Some(None) if !free[id] => free[id] = true,
+                _ => {
+                    return Err(SnapshotError::Inconsistent {
+                        reason: "free list names a mapped or repeated segment",
+                    })
+                }
+            }
+        }
+        snapshot.free_list = free_list;
+
+        Ok(snapshot)
+    }
+}
+
+/* the loader's reader only fails when the bytes run out */
+fn word(reader: &mut Reader) -> Result<u32, SnapshotError> {
+    reader.word().map_err(|_| SnapshotError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::crc32;
+    use crate::snapshot::{Snapshot, SnapshotError};
+    use crate::um::{HaltReason, UM};
+    use crate::umio::MemoryIo;
+
+    /* maps a segment and halts, then outputs a byte and halts again */
+    const PROGRAM: [u32; 6] = [
+        0xD2000061, /* r1 := 97 */
+        0x8000000A, /* r1 := map r2, giving segment 1 */
+        0xA0000000, /* out r0 */
+        0x70000000, /* halt */
+        0xA0000000, /* out r0 */
+        0x70000000, /* halt */
+    ];
+
+    fn bytes(snapshot: &Snapshot) -> Vec<u8> {
+        let mut out = Vec::new();
+        snapshot.write(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_snapshot_resumes() {
+        let mut machine = UM::with_io(MemoryIo::default());
+        machine.load_words(PROGRAM.to_vec());
+        machine.registers[2] = 3;
+        assert_eq!(machine.run(), HaltReason::Halted);
+        machine.memory.unmap_segment(1);
+        let snapshot = machine.snapshot();
+
+        let restored = Snapshot::read(&bytes(&snapshot)[..]).unwrap();
+        assert_eq!(restored, snapshot);
+        assert_eq!(restored.free_list, [1]);
+
+        let mut resumed = UM::with_io(MemoryIo::default());
+        resumed.restore(restored);
+        assert_eq!(resumed.pc, 4);
+        assert_eq!(resumed.memory.stats().live_segments, 0);
+        assert_eq!(resumed.run(), HaltReason::Halted);
+        assert_eq!(resumed.io.output, [0]);
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let mut machine = UM::with_io(MemoryIo::default());
+        machine.load_words(PROGRAM.to_vec());
+        let mut snapshot = machine.snapshot();
+        let good = bytes(&snapshot);
+
+        assert!(matches!(
+            Snapshot::read(&[0x70, 0, 0, 0][..]),
+            Err(SnapshotError::NotASnapshot)
+        ));
+
+        let mut corrupt = good.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Snapshot::read(&corrupt[..]),
+            Err(SnapshotError::BadChecksum { .. })
+        ));
+
+        /* segment 0 is mapped, so it cannot be free */
+        snapshot.free_list.push(0);
+        assert!(matches!(
+            Snapshot::read(&bytes(&snapshot)[..]),
+            Err(SnapshotError::Inconsistent { .. })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_hostile_slot_count() {
+        let mut machine = UM::with_io(MemoryIo::default());
+        machine.load_words(PROGRAM.to_vec());
+        let mut hostile = bytes(&machine.snapshot());
+
+        /* header, section kind and length, pc and registers, then slots */
+        hostile[56..60].copy_from_slice(&u32::MAX.to_be_bytes());
+        let checksum = crc32(&hostile[12..]);
+        hostile[8..12].copy_from_slice(&checksum.to_be_bytes());
+        assert!(matches!(
+            Snapshot::read(&hostile[..]),
+            Err(SnapshotError::Inconsistent { .. })
+        ));
+    }
+}
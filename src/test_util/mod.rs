@@ -0,0 +1,32 @@
+/* Fixtures shared by the unit tests */
+use crate::um::{UmOperations, UmWord};
+use crate::umio::UmIo;
+use std::io;
+
+pub fn three_reg(op: UmOperations, a: u32, b: u32, c: u32) -> UmWord {
+    ((op as u32) << 28) | (a << 6) | (b << 3) | c
+}
+
+pub fn load_value(a: u32, value: u32) -> UmWord {
+    ((UmOperations::LV as u32) << 28) | (a << 25) | value
+}
+
+/* has no input until some is pushed, like a nonblocking socket */
+#[derive(Default)]
+pub struct Pipe {
+    pub input: Option<u8>,
+    pub output: Vec<u8>,
+}
+
+impl UmIo for Pipe {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        match self.input.take() {
+            Some(byte) => Ok(Some(byte)),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+}
@@ -0,0 +1,312 @@
+use crate::um::{UmOperations, UmWord};
+use std::io::{self, Write};
+use std::ops::Range;
+
+/*
+ * Sees every instruction the machine executes. `ENABLED` is checked at
+ * compile time, so a machine built with `NoTrace` pays nothing for tracing.
+ */
+pub trait Tracer {
+    const ENABLED: bool = true;
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+/* The default tracer, which is compiled out entirely */
+#[derive(Default)]
+pub struct NoTrace;
+
+impl Tracer for NoTrace {
+    const ENABLED: bool = false;
+    fn trace(&mut self, _event: &TraceEvent) {}
+}
+
+/* Two tracers at once, e.g. a trace log and a profiler */
+impl<A: Tracer, B: Tracer> Tracer for (A, B) {
+    const ENABLED: bool = A::ENABLED || B::ENABLED;
+    fn trace(&mut self, event: &TraceEvent) {
+        if A::ENABLED {
+            self.0.trace(event);
+        }
+        if B::ENABLED {
+            self.1.trace(event);
+        }
+    }
+}
+
+/* A tracer picked at run time, e.g. from command line flags */
+impl<T: Tracer> Tracer for Option<T> {
+    const ENABLED: bool = T::ENABLED;
+    fn trace(&mut self, event: &TraceEvent) {
+        if let Some(tracer) = self {
+            tracer.trace(event);
+        }
+    }
+}
+
+/* One executed instruction */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TraceEvent {
+    pub pc: usize,
+    pub word: UmWord,
+    pub op: UmOperations,
+    pub before: [UmWord; 8],
+    pub after: [UmWord; 8],
+}
+
+/* A segment, and for SLOAD and SSTORE the offset within it */
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Access {
+    pub segment: UmWord,
+    pub offset: Option<UmWord>,
+}
+
+impl TraceEvent {
+    /* the registers the instruction names, in a b c order */
+    pub fn operands(&self) -> Vec<usize> {
+        let (a, b, c) = self.fields();
+        match self.op {
+            UmOperations::HALT => vec![],
+            UmOperations::LV => vec![a],
+            UmOperations::MAP | UmOperations::LOADP => vec![b, c],
+            UmOperations::UNMAP | UmOperations::OUT | UmOperations::IN => vec![c],
+            _ => vec![a, b, c],
+        }
+    }
+
+    pub fn access(&self) -> Option<Access> {
+        let (a, b, c) = self.fields();
+        let (segment, offset) = match self.op {
+            UmOperations::SLOAD => (self.before[b], Some(self.before[c])),
+            UmOperations::SSTORE => (self.before[a], Some(self.before[b])),
+            UmOperations::LOADP => (self.before[b], None),
+            UmOperations::MAP => (self.after[b], None),
+            UmOperations::UNMAP => (self.before[c], None),
+            _ => return None,
+        };
+        Some(Access { segment, offset })
+    }
+
+    /* the a b c register fields; LV keeps its register where the others keep `a` */
+    fn fields(&self) -> (usize, usize, usize) {
+        let word = self.word;
+        if self.op == UmOperations::LV {
+            return (((word >> 25) & 0x7) as usize, 0, 0);
+        }
+        (
+            ((word >> 6) & 0x7) as usize,
+            ((word >> 3) & 0x7) as usize,
+            (word & 0x7) as usize,
+        )
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TraceFormat {
+    /* one JSON object per line */
+    Json,
+    /* TRACE_MAGIC, then fixed size big-endian records, see `write_binary` */
+    Binary,
+}
+
+pub const TRACE_MAGIC: [u8; 4] = *b"UMTR";
+
+/* Which instructions make it into the trace; everything by default */
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub pcs: Option<Range<usize>>,
+    pub ops: Option<Vec<UmOperations>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, event: &TraceEvent) -> bool {
+        self.pcs.as_ref().is_none_or(|pcs| pcs.contains(&event.pc))
+            && self.ops.as_ref().is_none_or(|ops| ops.contains(&event.op))
+    }
+}
+
+/*
+ * Writes a trace to any Write. Tracing cannot stop the machine, so the
+ * first write error is kept and handed back by `finish`.
+ */
+pub struct TraceWriter<W: Write> {
+    out: W,
+    format: TraceFormat,
+    pub filter: TraceFilter,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(mut out: W, format: TraceFormat) -> Self {
+        let error = match format {
+            TraceFormat::Json => None,
+            TraceFormat::Binary => out.write_all(&TRACE_MAGIC).err(),
+        };
+        Self {
+            out,
+            format,
+            filter: TraceFilter::default(),
+            error,
+        }
+    }
+
+    /* flushes the trace and reports the first error writing it */
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+
+    fn write_json(&mut self, event: &TraceEvent) -> io::Result<()> {
+        write!(
+            self.out,
+            "{{\"pc\":{},\"word\":\"0x{:08x}\",\"op\":\"{:?}\",\"registers\":[",
+            event.pc, event.word, event.op
+        )?;
+        for (i, r) in event.operands().into_iter().enumerate() {
+            write!(
+                self.out,
+                "{}{{\"r\":{},\"before\":{},\"after\":{}}}",
+                if i == 0 { "" } else { "," },
+                r,
+                event.before[r],
+                event.after[r]
+            )?;
+        }
+        write!(self.out, "]")?;
+        if let Some(access) = event.access() {
+            write!(self.out, ",\"segment\":{}", access.segment)?;
+            if let Some(offset) = access.offset {
+                write!(self.out, ",\"offset\":{}", offset)?;
+            }
+        }
+        writeln!(self.out, "}}")
+    }
+
+    /*
+     * pc, word, then for each of the three operand slots the register and
+     * its value before and after (0xFF as the register if the slot is
+     * unused), then the segment and offset touched (0xFFFFFFFF if none),
+     * all big-endian: 43 bytes per record
+     */
+    fn write_binary(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let mut record = Vec::with_capacity(43);
+        record.extend_from_slice(&(event.pc as u32).to_be_bytes());
+        record.extend_from_slice(&event.word.to_be_bytes());
+        let operands = event.operands();
+        for slot in 0..3 {
+            match operands.get(slot) {
+                Some(&r) => {
+                    record.push(r as u8);
+                    record.extend_from_slice(&event.before[r].to_be_bytes());
+                    record.extend_from_slice(&event.after[r].to_be_bytes());
+                }
+                None => {
+                    record.push(0xFF);
+                    record.extend_from_slice(&[0; 8]);
+                }
+            }
+        }
+        let access = event.access();
+        let segment = access.map_or(!0, |access| access.segment);
+        let offset = access.and_then(|access| access.offset).unwrap_or(!0);
+        record.extend_from_slice(&segment.to_be_bytes());
+        record.extend_from_slice(&offset.to_be_bytes());
+        self.out.write_all(&record)
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        if self.error.is_some() || !self.filter.matches(event) {
+            return;
+        }
+        let result = match self.format {
+            TraceFormat::Json => self.write_json(event),
+            TraceFormat::Binary => self.write_binary(event),
+        };
+        self.error = result.err();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace::{TraceEvent, TraceFormat, TraceWriter, Tracer, TRACE_MAGIC};
+    use crate::um::{HaltReason, UmOperations, UM};
+    use crate::umio::MemoryIo;
+
+    /* keeps every event so tests can look at them */
+    #[derive(Default)]
+    struct Collect(Vec<TraceEvent>);
+
+    impl Tracer for Collect {
+        fn trace(&mut self, event: &TraceEvent) {
+            self.0.push(event.clone());
+        }
+    }
+
+    /* r1 := 1; r2 := map r1; m[r2][r0] := r1; halt */
+    const PROGRAM: [u32; 4] = [0xD2000001, 0x80000011, 0x20000081, 0x70000000];
+
+    fn traced<T: Tracer>(tracer: T) -> UM<MemoryIo, T> {
+        let mut machine = UM::with_io(MemoryIo::default()).with_tracer(tracer);
+        machine.load_words(PROGRAM.to_vec());
+        assert_eq!(machine.run(), HaltReason::Halted);
+        machine
+    }
+
+    #[test]
+    fn test_trace_events() {
+        let events = traced(Collect::default()).tracer.0;
+        let ops: Vec<UmOperations> = events.iter().map(|e| e.op).collect();
+        assert_eq!(
+            ops,
+            [
+                UmOperations::LV,
+                UmOperations::MAP,
+                UmOperations::SSTORE,
+                UmOperations::HALT
+            ]
+        );
+        assert_eq!(events[1].operands(), [2, 1]);
+        assert_eq!((events[1].before[2], events[1].after[2]), (0, 1));
+        let access = events[2].access().unwrap();
+        assert_eq!((access.segment, access.offset), (1, Some(0)));
+    }
+
+    #[test]
+    fn test_trace_faulting_instruction() {
+        /* r1 := 5; r0 := r1 / r2 */
+        let mut machine = UM::with_io(MemoryIo::default()).with_tracer(Collect::default());
+        machine.load_words(vec![0xD2000005, 0x5000000A]);
+        assert!(matches!(machine.run(), HaltReason::Fault(_)));
+
+        let events = machine.tracer.0;
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[1].pc, events[1].op), (1, UmOperations::DIV));
+        assert_eq!(events[1].before, events[1].after);
+    }
+
+    #[test]
+    fn test_trace_writer_json() {
+        let mut tracer = TraceWriter::new(Vec::new(), TraceFormat::Json);
+        tracer.filter.ops = Some(vec![UmOperations::SSTORE]);
+        let out = String::from_utf8(traced(tracer).tracer.out).unwrap();
+        assert_eq!(
+            out,
+            "{\"pc\":2,\"word\":\"0x20000081\",\"op\":\"SSTORE\",\"registers\":[\
+             {\"r\":2,\"before\":1,\"after\":1},{\"r\":0,\"before\":0,\"after\":0},\
+             {\"r\":1,\"before\":1,\"after\":1}],\"segment\":1,\"offset\":0}\n"
+        );
+    }
+
+    #[test]
+    fn test_trace_writer_binary() {
+        let mut tracer = TraceWriter::new(Vec::new(), TraceFormat::Binary);
+        tracer.filter.pcs = Some(0..2);
+        let out = traced(tracer).tracer.out;
+        assert_eq!(out[..4], TRACE_MAGIC);
+        assert_eq!(out.len(), 4 + 2 * 43);
+        assert_eq!(out[4..12], [0, 0, 0, 0, 0xD2, 0, 0, 1]);
+    }
+}
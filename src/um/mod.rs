@@ -1,15 +1,25 @@
-use crate::memory::Memory;
+use crate::fault::{FaultKind, UmFault};
+use crate::loader::{self, LoadError};
+use crate::memory::{Memory, Segment};
+use crate::snapshot::Snapshot;
+use crate::trace::{NoTrace, TraceEvent, Tracer};
+use crate::umio::{StdIo, UmIo};
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Read};
+use std::rc::Rc;
+use std::thread;
 
-pub struct UM {
+pub struct UM<IO: UmIo = StdIo, T: Tracer = NoTrace> {
     pub registers: [u32; 8],
     pub pc: usize,
     pub memory: Memory,
+    pub io: IO,
+    pub tracer: T,
 }
 pub type UmWord = u32;
 type UmInstruction = u32;
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum UmOperations {
     /* Will work like a C enum with indexing if you cast with `as` */
     CMOV = 0,
@@ -29,83 +39,380 @@ pub enum UmOperations {
 }
 pub type UmOp = UmOperations;
 
+/* Why the machine stopped */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum HaltReason {
+    /* the program executed HALT */
+    Halted,
+    /* the pc ran past the end of segment 0, which the spec makes a failure */
+    PcOutOfBounds { pc: usize },
+    Fault(UmFault),
+}
+
+/* What happened when the machine was asked to make progress */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum StepResult {
+    /* the instruction executed and the machine can keep going */
+    Running,
+    /* the machine stopped, by HALT, by running off the program or by a fault */
+    Halted(HaltReason),
+    /*
+     * IN found no input available yet. The pc is left on the IN
+     * instruction so the next step retries it.
+     */
+    WaitingForInput,
+    /* run_for used up its instruction budget */
+    OutOfFuel,
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HaltReason::Halted => write!(f, "halted"),
+            HaltReason::PcOutOfBounds { pc } => {
+                write!(f, "ran off the end of the program at pc {}", pc)
+            }
+            HaltReason::Fault(fault) => write!(f, "{}", fault),
+        }
+    }
+}
+
+impl Default for UM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UmOperations {
+    pub fn decode(instr: UmWord) -> Option<UmOperations> {
+        match instr >> 28 {
+            0 => Some(UmOperations::CMOV),
+            1 => Some(UmOperations::SLOAD),
+            2 => Some(UmOperations::SSTORE),
+            3 => Some(UmOperations::ADD),
+            4 => Some(UmOperations::MUL),
+            5 => Some(UmOperations::DIV),
+            6 => Some(UmOperations::NAND),
+            7 => Some(UmOperations::HALT),
+            8 => Some(UmOperations::MAP),
+            9 => Some(UmOperations::UNMAP),
+            10 => Some(UmOperations::OUT),
+            11 => Some(UmOperations::IN),
+            12 => Some(UmOperations::LOADP),
+            13 => Some(UmOperations::LV),
+            _ => None,
+        }
+    }
+}
+
 impl UM {
     pub fn new() -> Self {
+        UM::with_io(StdIo::new())
+    }
+
+    /* a machine on stdin and stdout with `words` as segment 0 */
+    pub fn from_words(words: Vec<UmWord>) -> Self {
+        let mut machine = UM::new();
+        machine.load_words(words);
+        machine
+    }
+}
+
+impl<IO: UmIo> UM<IO> {
+    pub fn with_io(io: IO) -> Self {
         Self {
             registers: [0 as UmInstruction; 8],
             pc: 0,
             memory: Memory::new(),
+            io,
+            tracer: NoTrace,
         }
     }
+}
 
-    pub fn init_program(&mut self, path: &str) {
-        let mut file = File::open(path).expect("Could not read program. Exiting...");
+impl<IO: UmIo, T: Tracer> UM<IO, T> {
+    /* the same machine, reporting each instruction it executes to `tracer` */
+    pub fn with_tracer<U: Tracer>(self, tracer: U) -> UM<IO, U> {
+        UM {
+            registers: self.registers,
+            pc: self.pc,
+            memory: self.memory,
+            io: self.io,
+            tracer,
+        }
+    }
 
-        let mut bytes = Vec::new();
+    /* everything needed to resume the machine later; segments are shared */
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            pc: self.pc,
+            segments: self.memory.segments.clone(),
+            free_list: self.memory.free_list.clone(),
+        }
+    }
 
-        file.read_to_end(&mut bytes)
-            .expect("Could not read program. Exiting...");
+    /* picks up where `snapshot` left off; the I/O handle is kept */
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        let limits = self.memory.limits();
+        self.memory = Memory::with_segments(snapshot.segments, snapshot.free_list);
+        self.memory.set_limits(limits);
+    }
 
-        let mut instructions: Vec<UmInstruction> = Vec::new();
-        for i in (0..bytes.len()).step_by(4) {
-            let word: UmWord =
-                UmInstruction::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
-            instructions.push(word);
-        }
+    pub fn init_program(&mut self, path: &str) -> Result<(), LoadError> {
+        self.load_program(File::open(path)?)
+    }
 
-        self.memory.segments[0] = Some(instructions);
+    /*
+     * reads a raw image or container from a file, pipe or byte slice into
+     * segment 0, and moves the pc to its entry point
+     */
+    pub fn load_program<R: Read>(&mut self, reader: R) -> Result<(), LoadError> {
+        let image = loader::read_image(reader)?;
+        self.load_words(image.words);
+        self.pc = image.entry as usize;
+        Ok(())
     }
 
-    pub fn run(&mut self) {
+    pub fn load_words(&mut self, words: Vec<UmWord>) {
+        self.memory.segments[0] = Some(words.into());
+    }
+
+    /* runs to completion, waiting for input whenever the I/O handle has none */
+    pub fn run(&mut self) -> HaltReason {
         loop {
-            let program = self.memory.segments[0].as_ref().unwrap();
-            if self.pc >= program.len() {
-                break;
-            }
-
-            /* fetch */
-            let instr = program[self.pc];
-            /* increment pc upon fetch */
-            self.pc += 1;
-
-            /* decode */
-            let current_opcode = match instr >> 28 {
-                0 => UmOperations::CMOV,
-                1 => UmOperations::SLOAD,
-                2 => UmOperations::SSTORE,
-                3 => UmOperations::ADD,
-                4 => UmOperations::MUL,
-                5 => UmOperations::DIV,
-                6 => UmOperations::NAND,
-                7 => UmOperations::HALT,
-                8 => UmOperations::MAP,
-                9 => UmOperations::UNMAP,
-                10 => UmOperations::OUT,
-                11 => UmOperations::IN,
-                12 => UmOperations::LOADP,
-                13 => UmOperations::LV,
-                _ => panic!("Invalid opcode in fetch: {}", instr >> 28),
-            };
-            match current_opcode {
-                UmOperations::HALT => break,
-                UmOperations::LV => {
-                    let (a, val) = (((instr >> 25) & 0x7) as usize, instr & 0x1FFFFFF);
-                    self.registers[a] = val;
+            /* same as step, without building a StepResult per instruction */
+            match self.cycle() {
+                Ok(None) => {}
+                Ok(Some(reason)) => return reason,
+                Err(fault) => match self.interrupt(fault) {
+                    StepResult::Halted(reason) => return reason,
+                    _ => thread::yield_now(),
+                },
+            }
+        }
+    }
+
+    /* executes at most `fuel` instructions; the machine can be resumed after */
+    pub fn run_for(&mut self, fuel: u64) -> StepResult {
+        for _ in 0..fuel {
+            match self.step() {
+                StepResult::Running => {}
+                result => return result,
+            }
+        }
+
+        StepResult::OutOfFuel
+    }
+
+    /*
+     * executes one instruction. Pending output is flushed whenever the
+     * machine halts or faults.
+     */
+    #[inline(always)]
+    pub fn step(&mut self) -> StepResult {
+        match self.cycle() {
+            Ok(None) => StepResult::Running,
+            Ok(Some(reason)) => StepResult::Halted(reason),
+            Err(fault) => self.interrupt(fault),
+        }
+    }
+
+    #[cold]
+    fn interrupt(&mut self, fault: UmFault) -> StepResult {
+        if fault.kind == FaultKind::Io(io::ErrorKind::WouldBlock)
+            && fault.opcode == UmOperations::IN as u32
+        {
+            self.pc = fault.pc;
+            return StepResult::WaitingForInput;
+        }
+
+        /* the fault is more interesting than a failed flush */
+        let _ = self.io.flush();
+        StepResult::Halted(HaltReason::Fault(fault))
+    }
+
+    #[inline(always)]
+    fn cycle(&mut self) -> Result<Option<HaltReason>, UmFault> {
+        /* fetch */
+        let program = self.memory.segments[0].as_deref().unwrap_or(&[]);
+        let instr = match program.get(self.pc) {
+            Some(&instr) => instr,
+            None => {
+                return match self.io.flush() {
+                    Ok(()) => Ok(Some(HaltReason::PcOutOfBounds { pc: self.pc })),
+                    Err(e) => Err(self.fault_at(FaultKind::Io(e.kind()), self.pc, 0)),
+                };
+            }
+        };
+        /* only kept when tracing */
+        let (pc, before) = if T::ENABLED {
+            (self.pc, self.registers)
+        } else {
+            (0, [0; 8])
+        };
+        /* increment pc upon fetch */
+        self.pc += 1;
+
+        /* decode */
+        let current_opcode = match UmOperations::decode(instr) {
+            Some(op) => op,
+            None => return Err(self.fault(FaultKind::InvalidOpcode, instr)),
+        };
+        match current_opcode {
+            UmOperations::HALT => {
+                if T::ENABLED {
+                    self.trace(pc, instr, current_opcode, before);
                 }
-                _ => {
-                    let (a, b, c) = (
-                        ((instr >> 6) & 0x7) as usize,
-                        ((instr >> 3) & 0x7) as usize,
-                        (instr & 0x7) as usize,
-                    );
-                    self.execute(current_opcode, a, b, c);
+                return match self.io.flush() {
+                    Ok(()) => Ok(Some(HaltReason::Halted)),
+                    Err(e) => Err(self.fault(FaultKind::Io(e.kind()), instr)),
+                };
+            }
+            UmOperations::LV => {
+                let (a, val) = (((instr >> 25) & 0x7) as usize, instr & 0x1FFFFFF);
+                self.registers[a] = val;
+            }
+            _ => {
+                let (a, b, c) = (
+                    ((instr >> 6) & 0x7) as usize,
+                    ((instr >> 3) & 0x7) as usize,
+                    (instr & 0x7) as usize,
+                );
+                if let Err(kind) = self.execute(current_opcode, a, b, c) {
+                    /*
+                     * nothing was written, so after == before. An IN that
+                     * would block runs again later and is traced then.
+                     */
+                    if T::ENABLED && kind != FaultKind::Io(io::ErrorKind::WouldBlock) {
+                        self.trace(pc, instr, current_opcode, before);
+                    }
+                    return Err(self.fault(kind, instr));
                 }
             }
         }
+        if T::ENABLED {
+            self.trace(pc, instr, current_opcode, before);
+        }
+
+        Ok(None)
+    }
+
+    fn trace(&mut self, pc: usize, word: UmWord, op: UmOperations, before: [UmWord; 8]) {
+        self.tracer.trace(&TraceEvent {
+            pc,
+            word,
+            op,
+            before,
+            after: self.registers,
+        });
+    }
+
+    /* the pc has already moved past the faulting instruction */
+    #[cold]
+    fn fault(&self, kind: FaultKind, instr: UmInstruction) -> UmFault {
+        self.fault_at(kind, self.pc - 1, instr)
+    }
+
+    #[cold]
+    fn fault_at(&self, kind: FaultKind, pc: usize, instr: UmInstruction) -> UmFault {
+        UmFault {
+            kind,
+            pc,
+            instruction: instr,
+            opcode: instr >> 28,
+            registers: self.registers,
+        }
+    }
+
+    /*
+     * Segment access. The default build turns every bad access into a
+     * fault; with the `unchecked` feature the program is trusted and a bad
+     * access is undefined behaviour instead.
+     */
+    #[cfg(not(feature = "unchecked"))]
+    #[inline(always)]
+    fn segment(&self, seg: UmWord) -> Result<&Segment, FaultKind> {
+        match self.memory.segments.get(seg as usize) {
+            Some(Some(segment)) => Ok(segment),
+            _ => Err(FaultKind::UnmappedSegment { segment: seg }),
+        }
+    }
+
+    #[cfg(not(feature = "unchecked"))]
+    #[inline(always)]
+    fn load(&self, seg: UmWord, offset: UmWord) -> Result<UmWord, FaultKind> {
+        let segment = self.segment(seg)?;
+        match segment.get(offset as usize) {
+            Some(&word) => Ok(word),
+            None => Err(FaultKind::OutOfBounds {
+                segment: seg,
+                offset,
+                len: segment.len(),
+            }),
+        }
+    }
+
+    #[cfg(not(feature = "unchecked"))]
+    #[inline(always)]
+    fn store(&mut self, seg: UmWord, offset: UmWord, value: UmWord) -> Result<(), FaultKind> {
+        let len = self.segment(seg)?.len();
+        if offset as usize >= len {
+            return Err(FaultKind::OutOfBounds {
+                segment: seg,
+                offset,
+                len,
+            });
+        }
+        if let Some(Some(segment)) = self.memory.segments.get_mut(seg as usize) {
+            /* copies the words only if another segment shares them */
+            Rc::make_mut(segment)[offset as usize] = value;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "unchecked")]
+    #[inline(always)]
+    fn segment(&self, seg: UmWord) -> Result<&Segment, FaultKind> {
+        /* SAFETY: the program never names an unmapped segment */
+        unsafe {
+            Ok(self
+                .memory
+                .segments
+                .get_unchecked(seg as usize)
+                .as_ref()
+                .unwrap_unchecked())
+        }
+    }
+
+    #[cfg(feature = "unchecked")]
+    #[inline(always)]
+    fn load(&self, seg: UmWord, offset: UmWord) -> Result<UmWord, FaultKind> {
+        let segment = self.segment(seg)?;
+        /* SAFETY: the program stays within the segments it maps */
+        unsafe { Ok(*segment.get_unchecked(offset as usize)) }
+    }
+
+    #[cfg(feature = "unchecked")]
+    #[inline(always)]
+    fn store(&mut self, seg: UmWord, offset: UmWord, value: UmWord) -> Result<(), FaultKind> {
+        /* SAFETY: as for `segment` and `load` */
+        unsafe {
+            let segment = self
+                .memory
+                .segments
+                .get_unchecked_mut(seg as usize)
+                .as_mut()
+                .unwrap_unchecked();
+            *Rc::make_mut(segment).get_unchecked_mut(offset as usize) = value;
+        }
+        Ok(())
     }
 
     #[inline(always)]
-    fn execute(&mut self, op: UmOp, a: usize, b: usize, c: usize) {
+    fn execute(&mut self, op: UmOp, a: usize, b: usize, c: usize) -> Result<(), FaultKind> {
         match op {
             UmOperations::CMOV => {
                 if self.registers[c] != 0 {
@@ -113,20 +420,10 @@ impl UM {
                 }
             }
             UmOperations::SLOAD => {
-                let seg = self.registers[b] as usize;
-                let offset = self.registers[c] as usize;
-                self.registers[a] = match &(self.memory.segments[seg]) {
-                    Some(segment) => segment[offset],
-                    None => panic!("No segment at {}", seg),
-                }
+                self.registers[a] = self.load(self.registers[b], self.registers[c])?
             }
             UmOperations::SSTORE => {
-                let seg = self.registers[a] as usize;
-                let offset = self.registers[b] as usize;
-                match &mut (self.memory.segments[seg]) {
-                    Some(segment) => segment[offset] = self.registers[c],
-                    None => panic!("No segment at {}", seg),
-                }
+                self.store(self.registers[a], self.registers[b], self.registers[c])?
             }
             UmOperations::ADD => {
                 self.registers[a] = self.registers[b].wrapping_add(self.registers[c])
@@ -134,35 +431,392 @@ impl UM {
             UmOperations::MUL => {
                 self.registers[a] = self.registers[b].wrapping_mul(self.registers[c])
             }
-            UmOperations::DIV => self.registers[a] = self.registers[b] / self.registers[c],
+            UmOperations::DIV => {
+                if self.registers[c] == 0 {
+                    return Err(FaultKind::DivideByZero);
+                }
+                self.registers[a] = self.registers[b] / self.registers[c]
+            }
             UmOperations::NAND => self.registers[a] = !(self.registers[b] & self.registers[c]),
             UmOperations::MAP => {
-                let size = self.registers[c] as usize;
-                self.registers[b] = self.memory.map_segment(size) as u32;
+                let size = self.registers[c];
+                self.registers[b] = self
+                    .memory
+                    .map_segment(size as usize)
+                    .map_err(|limit| FaultKind::OutOfMemory { size, limit })?
+                    as u32;
+            }
+            UmOperations::UNMAP => {
+                let seg = self.registers[c];
+                if seg == 0 {
+                    return Err(FaultKind::UnmapZero);
+                }
+                /* checked even in the unchecked build, it is not a hot path */
+                if !matches!(self.memory.segments.get(seg as usize), Some(Some(_))) {
+                    return Err(FaultKind::UnmapInactive { segment: seg });
+                }
+                self.memory.unmap_segment(seg as usize);
             }
-            UmOperations::UNMAP => self.memory.unmap_segment(self.registers[c] as usize),
             UmOperations::OUT => {
-                print!("{}", (self.registers[c] & 0xFF) as u8 as char);
+                let value = self.registers[c];
+                if value > 0xFF {
+                    return Err(FaultKind::InvalidOutput { value });
+                }
+                self.io
+                    .write_byte(value as u8)
+                    .map_err(|e| FaultKind::Io(e.kind()))?;
             }
             UmOperations::IN => {
-                let mut buf = [0u8; 1];
-                match io::stdin().read_exact(&mut buf) {
-                    Ok(_) => self.registers[c] = buf[0] as u32,
-                    Err(_) => self.registers[c] = 0xFFFFFFFFu32,
+                self.registers[c] = match self.io.read_byte() {
+                    Ok(Some(byte)) => byte as u32,
+                    Ok(None) => 0xFFFFFFFFu32,
+                    Err(e) => return Err(FaultKind::Io(e.kind())),
                 }
             }
             UmOperations::LOADP => {
                 if self.registers[b] != 0 {
-                    let seg = self.registers[b] as usize;
-                    let duplicate = match &(self.memory.segments[seg]) {
-                        Some(segment) => segment.clone(),
-                        None => panic!("No segment at {}", seg),
-                    };
-                    self.memory.segments[0] = Some(duplicate);
+                    /* no copy: segment 0 is written copy-on-write like any other */
+                    let program = self.segment(self.registers[b])?.clone();
+                    self.memory.segments[0] = Some(program);
                 }
                 self.pc = self.registers[c] as usize;
             }
             _ => unreachable!(),
         }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fault::FaultKind;
+    use crate::loader::LoadError;
+    use crate::memory::{LimitExceeded, MemoryLimits};
+    use crate::test_util::{load_value, three_reg, Pipe};
+    use crate::um::{HaltReason, StepResult, UmOperations, UmWord, UM};
+    use crate::umio::{MemoryIo, UmIo};
+    use std::io;
+    use std::rc::Rc;
+
+    /* counts flushes so tests can see when output would reach the terminal */
+    #[derive(Default)]
+    struct FlushCounter {
+        flushes: usize,
+    }
+
+    impl UmIo for FlushCounter {
+        fn read_byte(&mut self) -> io::Result<Option<u8>> {
+            Ok(None)
+        }
+        fn write_byte(&mut self, _byte: u8) -> io::Result<()> {
+            Ok(())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    fn machine(program: Vec<UmWord>) -> UM {
+        let mut machine = UM::new();
+        machine.memory.segments[0] = Some(program.into());
+        machine
+    }
+
+    fn fault(reason: HaltReason) -> crate::fault::UmFault {
+        match reason {
+            HaltReason::Fault(fault) => fault,
+            other => panic!("expected a fault, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_off_end() {
+        let mut um = machine(vec![load_value(1, 6)]);
+        assert_eq!(um.run(), HaltReason::PcOutOfBounds { pc: 1 });
+        assert_eq!(um.registers[1], 6);
+
+        /* a jump past the end is caught the same way */
+        let mut um = machine(vec![
+            load_value(1, 9),
+            three_reg(UmOperations::LOADP, 0, 0, 1),
+        ]);
+        assert_eq!(um.step(), StepResult::Running);
+        assert_eq!(um.step(), StepResult::Running);
+        assert_eq!(
+            um.step(),
+            StepResult::Halted(HaltReason::PcOutOfBounds { pc: 9 })
+        );
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut um = machine(vec![
+            load_value(1, 6),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        assert_eq!(um.run(), HaltReason::Halted);
+        assert_eq!(um.registers[1], 6);
+    }
+
+    #[test]
+    fn test_load_program() {
+        let mut um = UM::with_io(MemoryIo::default());
+        let bytes: &[u8] = &[0xD2, 0, 0, 0x48, 0xA0, 0, 0, 1, 0x70, 0, 0, 0];
+        um.load_program(bytes).unwrap();
+        assert_eq!(um.run(), HaltReason::Halted);
+        assert_eq!(um.io.output, b"H");
+
+        assert!(matches!(
+            um.load_program(&bytes[..5]),
+            Err(LoadError::TrailingBytes { len: 5 })
+        ));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut um = machine(vec![
+            load_value(1, 6),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        assert_eq!(um.step(), StepResult::Running);
+        assert_eq!((um.pc, um.registers[1]), (1, 6));
+        assert_eq!(um.step(), StepResult::Halted(HaltReason::Halted));
+    }
+
+    #[test]
+    fn test_run_for_resumes() {
+        /* r1 counts up forever */
+        let mut um = machine(vec![
+            load_value(2, 1),
+            three_reg(UmOperations::ADD, 1, 1, 2),
+            load_value(3, 1),
+            three_reg(UmOperations::LOADP, 0, 0, 3),
+        ]);
+        assert_eq!(um.run_for(0), StepResult::OutOfFuel);
+        assert_eq!(um.run_for(4), StepResult::OutOfFuel);
+        assert_eq!((um.pc, um.registers[1]), (1, 1));
+        assert_eq!(um.run_for(30), StepResult::OutOfFuel);
+        assert_eq!(um.registers[1], 11);
+    }
+
+    #[test]
+    fn test_run_for_stops_at_halt() {
+        let mut um = UM::with_io(MemoryIo::default());
+        um.load_words(vec![
+            load_value(1, 'a' as u32),
+            three_reg(UmOperations::OUT, 0, 0, 1),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        assert_eq!(um.run_for(100), StepResult::Halted(HaltReason::Halted));
+        assert_eq!(um.io.output, b"a");
+    }
+
+    #[test]
+    fn test_waiting_for_input() {
+        let mut um = UM::with_io(Pipe::default());
+        um.load_words(vec![
+            three_reg(UmOperations::IN, 0, 0, 1),
+            three_reg(UmOperations::OUT, 0, 0, 1),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        assert_eq!(um.run_for(10), StepResult::WaitingForInput);
+        assert_eq!(um.step(), StepResult::WaitingForInput);
+        assert_eq!(um.pc, 0);
+
+        um.io.input = Some(b'x');
+        assert_eq!(um.run_for(10), StepResult::Halted(HaltReason::Halted));
+        assert_eq!(um.io.output, b"x");
+    }
+
+    #[test]
+    fn test_flush_on_halt_and_fault() {
+        let mut um = UM::with_io(FlushCounter::default());
+        um.load_words(vec![
+            three_reg(UmOperations::OUT, 0, 0, 1),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        assert_eq!(um.run(), HaltReason::Halted);
+        assert_eq!(um.io.flushes, 1);
+
+        let mut um = UM::with_io(FlushCounter::default());
+        um.memory.segments[0] = Some(vec![three_reg(UmOperations::DIV, 1, 2, 3)].into());
+        assert!(matches!(um.run(), HaltReason::Fault(_)));
+        assert_eq!(um.io.flushes, 1);
+    }
+
+    #[test]
+    fn test_sstore_into_segment_zero() {
+        /* overwrites the invalid instruction at 3 with a no-op */
+        let mut um = machine(vec![
+            load_value(1, 3),
+            load_value(2, 0),
+            three_reg(UmOperations::SSTORE, 0, 1, 2),
+            0xE0000000,
+            three_reg(UmOperations::HALT, 0, 0, 0),
+        ]);
+        assert_eq!(um.run(), HaltReason::Halted);
+    }
+
+    #[test]
+    fn test_loadp_replaces_code() {
+        /* builds a one word program holding halt and jumps into it */
+        let mut um = machine(vec![
+            load_value(1, 1),
+            three_reg(UmOperations::MAP, 0, 1, 1),
+            load_value(3, 0x70),
+            load_value(4, 0x1000000),
+            three_reg(UmOperations::MUL, 3, 3, 4),
+            three_reg(UmOperations::SSTORE, 1, 0, 3),
+            three_reg(UmOperations::LOADP, 0, 1, 0),
+        ]);
+        assert_eq!(um.run(), HaltReason::Halted);
+        assert_eq!(um.pc, 1);
+        assert_eq!(um.memory.segments[0].as_deref(), Some(&[0x70000000][..]));
+    }
+
+    #[test]
+    fn test_loadp_shares_segment() {
+        let mut um = machine(vec![three_reg(UmOperations::LOADP, 0, 1, 2)]);
+        um.memory
+            .segments
+            .push(Some(vec![three_reg(UmOperations::HALT, 0, 0, 0)].into()));
+        um.registers[1] = 1;
+        assert_eq!(um.run(), HaltReason::Halted);
+
+        let (code, loaded) = (&um.memory.segments[0], &um.memory.segments[1]);
+        assert!(Rc::ptr_eq(code.as_ref().unwrap(), loaded.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_loadp_copy_on_write() {
+        /*
+         * segment 1 holds a copy of the program; after loading it, patching
+         * segment 0 must leave segment 1 alone and reloading it undoes the
+         * patch
+         */
+        let program = vec![
+            load_value(1, 1),
+            load_value(2, 4),
+            three_reg(UmOperations::LOADP, 0, 1, 2),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+            three_reg(UmOperations::SSTORE, 0, 5, 6),
+            three_reg(UmOperations::LOADP, 0, 0, 5),
+            three_reg(UmOperations::HALT, 0, 0, 0),
+            0xE0000000,
+        ];
+        let mut um = machine(program.clone());
+        um.memory.segments.push(Some(program.clone().into()));
+        um.registers[5] = 7;
+        um.registers[6] = three_reg(UmOperations::HALT, 0, 0, 0);
+        assert_eq!(um.run(), HaltReason::Halted);
+        assert_eq!(um.memory.segments[1].as_deref(), Some(&program[..]));
+
+        /* the patched halt at 7 is gone again once segment 1 is reloaded */
+        um.registers[2] = 7;
+        um.pc = 2;
+        assert_eq!(fault(um.run()).kind, FaultKind::InvalidOpcode);
+    }
+
+    #[test]
+    fn test_fault_divide_by_zero() {
+        let mut um = machine(vec![
+            load_value(2, 7),
+            three_reg(UmOperations::DIV, 1, 2, 3),
+        ]);
+        let fault = fault(um.run());
+        assert_eq!(fault.kind, FaultKind::DivideByZero);
+        assert_eq!(fault.pc, 1);
+        assert_eq!(fault.opcode, UmOperations::DIV as u32);
+        assert_eq!(fault.registers[2], 7);
+    }
+
+    #[test]
+    fn test_fault_invalid_opcode() {
+        let mut um = machine(vec![0xE0000000]);
+        let fault = fault(um.run());
+        assert_eq!(fault.kind, FaultKind::InvalidOpcode);
+        assert_eq!(fault.instruction, 0xE0000000);
+        assert_eq!(fault.opcode, 14);
+    }
+
+    #[test]
+    #[cfg(not(feature = "unchecked"))]
+    fn test_fault_unmapped_segment() {
+        let mut um = machine(vec![
+            load_value(2, 3),
+            three_reg(UmOperations::SLOAD, 1, 2, 0),
+        ]);
+        assert_eq!(
+            fault(um.run()).kind,
+            FaultKind::UnmappedSegment { segment: 3 }
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "unchecked"))]
+    fn test_fault_out_of_bounds() {
+        let mut um = machine(vec![
+            load_value(3, 4),
+            three_reg(UmOperations::MAP, 0, 1, 3),
+            three_reg(UmOperations::SSTORE, 1, 3, 0),
+        ]);
+        assert_eq!(
+            fault(um.run()).kind,
+            FaultKind::OutOfBounds {
+                segment: 1,
+                offset: 4,
+                len: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_fault_unmap() {
+        let mut um = machine(vec![three_reg(UmOperations::UNMAP, 0, 0, 0)]);
+        assert_eq!(fault(um.run()).kind, FaultKind::UnmapZero);
+
+        let mut um = machine(vec![
+            load_value(1, 1),
+            three_reg(UmOperations::UNMAP, 0, 0, 1),
+        ]);
+        assert_eq!(
+            fault(um.run()).kind,
+            FaultKind::UnmapInactive { segment: 1 }
+        );
+    }
+
+    #[test]
+    fn test_fault_out_of_memory() {
+        let mut um = machine(vec![
+            load_value(1, 100),
+            three_reg(UmOperations::MAP, 0, 2, 1),
+        ]);
+        um.memory.set_limits(MemoryLimits {
+            max_segment_words: Some(64),
+            ..MemoryLimits::default()
+        });
+        assert_eq!(
+            fault(um.run()).kind,
+            FaultKind::OutOfMemory {
+                size: 100,
+                limit: LimitExceeded::SegmentWords(64)
+            }
+        );
+        assert_eq!(um.registers[2], 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "unchecked"))]
+    fn test_fault_loadp_unmapped() {
+        let mut um = machine(vec![
+            load_value(1, 2),
+            three_reg(UmOperations::LOADP, 0, 1, 0),
+        ]);
+        assert_eq!(
+            fault(um.run()).kind,
+            FaultKind::UnmappedSegment { segment: 2 }
+        );
     }
 }
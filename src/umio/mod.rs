@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Stdin, Stdout, Write};
+
+/* Where OUT sends bytes and IN gets them from */
+pub trait UmIo {
+    /* returns None once input is exhausted */
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/*
+ * The process's stdin and stdout. Output is buffered and only reaches the
+ * terminal when the machine asks for input, halts, faults or is flushed.
+ */
+pub struct StdIo {
+    stdin: Stdin,
+    stdout: BufWriter<Stdout>,
+}
+
+impl StdIo {
+    pub fn new() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: BufWriter::with_capacity(1 << 16, io::stdout()),
+        }
+    }
+}
+
+impl Default for StdIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UmIo for StdIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        /* interactive programs need to show their prompt before blocking */
+        self.stdout.flush()?;
+
+        let mut buf = [0u8; 1];
+        match self.stdin.read_exact(&mut buf) {
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.stdout.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+/*
+ * Any reader and writer, such as files picked on the command line. Like
+ * `StdIo`, output is flushed before each read so prompts show up.
+ */
+pub struct StreamIo<R: Read, W: Write> {
+    input: BufReader<R>,
+    output: BufWriter<W>,
+}
+
+impl<R: Read, W: Write> StreamIo<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input: BufReader::new(input),
+            output: BufWriter::with_capacity(1 << 16, output),
+        }
+    }
+}
+
+impl<R: Read, W: Write> UmIo for StreamIo<R, W> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.output.flush()?;
+
+        let mut buf = [0u8; 1];
+        match self.input.read_exact(&mut buf) {
+            Ok(_) => Ok(Some(buf[0])),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/* Reads from a fixed input buffer and collects output in memory */
+#[derive(Default)]
+pub struct MemoryIo {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>,
+}
+
+impl MemoryIo {
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl UmIo for MemoryIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.output.push(byte);
+        Ok(())
+    }
+}
+
+/*
+ * Feeds the machine one line of input at a time, and keeps a transcript of
+ * the session with each line echoed where the machine asked for it
+ */
+#[derive(Default)]
+pub struct ScriptedIo {
+    lines: VecDeque<String>,
+    pending: VecDeque<u8>,
+    pub transcript: Vec<u8>,
+}
+
+impl ScriptedIo {
+    pub fn new<I, S>(lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            lines: lines.into_iter().map(Into::into).collect(),
+            pending: VecDeque::new(),
+            transcript: Vec::new(),
+        }
+    }
+
+    /* lines that have not been read by the machine yet */
+    pub fn remaining(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+impl UmIo for ScriptedIo {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.pending.is_empty() {
+            match self.lines.pop_front() {
+                Some(line) => {
+                    self.pending.extend(line.bytes());
+                    self.pending.push_back(b'\n');
+                    self.transcript.extend(self.pending.iter());
+                }
+                None => return Ok(None),
+            }
+        }
+
+        Ok(self.pending.pop_front())
+    }
+
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.transcript.push(byte);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::umio::{MemoryIo, ScriptedIo, StreamIo, UmIo};
+
+    #[test]
+    fn test_memory_io() {
+        let mut io = MemoryIo::new(b"ab");
+        assert_eq!(io.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(io.read_byte().unwrap(), Some(b'b'));
+        assert_eq!(io.read_byte().unwrap(), None);
+        io.write_byte(b'z').unwrap();
+        assert_eq!(io.output, b"z");
+    }
+
+    #[test]
+    fn test_scripted_io_transcript() {
+        let mut io = ScriptedIo::new(["hi"]);
+        io.write_byte(b'>').unwrap();
+        assert_eq!(io.read_byte().unwrap(), Some(b'h'));
+        assert_eq!(io.remaining(), 0);
+        assert_eq!(io.read_byte().unwrap(), Some(b'i'));
+        assert_eq!(io.read_byte().unwrap(), Some(b'\n'));
+        assert_eq!(io.read_byte().unwrap(), None);
+        assert_eq!(io.transcript, b">hi\n");
+    }
+
+    #[test]
+    fn test_stream_io() {
+        let mut out = Vec::new();
+        let mut io = StreamIo::new(&b"a"[..], &mut out);
+        io.write_byte(b'>').unwrap();
+        assert_eq!(io.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(io.read_byte().unwrap(), None);
+        io.write_byte(b'!').unwrap();
+        io.flush().unwrap();
+        drop(io);
+        assert_eq!(out, b">!");
+    }
+}
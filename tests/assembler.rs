@@ -0,0 +1,35 @@
+use std::fs;
+use um::assembler::UMAssembler;
+use um::um::{HaltReason, UmWord, UM};
+use um::umio::MemoryIo;
+
+fn words(path: &str) -> Vec<UmWord> {
+    fs::read(path)
+        .unwrap()
+        .chunks(4)
+        .map(|b| UmWord::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[test]
+fn test_commented_cat_matches_binary() {
+    let program = UMAssembler {}.read_asm_code("tests/cat-new.ums").unwrap();
+    assert_eq!(program, words("tests/cat.um"));
+}
+
+#[test]
+fn test_add_two_numbers_matches_binary() {
+    let program = UMAssembler {}
+        .read_asm_code("tests/add_two_numbers.ums")
+        .unwrap();
+    assert_eq!(program, words("tests/add_two_numbers.um"));
+}
+
+#[test]
+fn test_hello_from_data() {
+    let program = UMAssembler {}.read_asm_code("tests/hello.ums").unwrap();
+    let mut machine = UM::with_io(MemoryIo::default());
+    machine.load_words(program);
+    assert_eq!(machine.run(), HaltReason::Halted);
+    assert_eq!(machine.io.output, b"Hello, world.\n");
+}
@@ -0,0 +1,164 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn um(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_um"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn ums(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ums"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/* a scratch directory of its own for each test */
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("um-cli-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_halt_exits_zero() {
+    let output = um(&["tests/hello.um"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Hello, world.\n");
+}
+
+#[test]
+fn test_run_off_end_exits_two() {
+    let output = um(&["tests/add_two_numbers.um"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_stdin_and_output_files() {
+    let path = std::env::temp_dir().join(format!("um-cli-{}.out", std::process::id()));
+    let output = um(&[
+        "--stdin-file",
+        "tests/hello.um",
+        "--output-file",
+        path.to_str().unwrap(),
+        "tests/cat.um",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read("tests/hello.um").unwrap()
+    );
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_limits_exit_codes() {
+    assert_eq!(
+        um(&["--max-instructions", "10", "tests/hello.um"])
+            .status
+            .code(),
+        Some(21)
+    );
+    assert_eq!(
+        um(&["--max-memory", "10", "tests/midmark.um"])
+            .status
+            .code(),
+        Some(20)
+    );
+}
+
+#[test]
+fn test_default_memory_limit() {
+    /* r1 := 0x1FFFFFF; r1 := r1 * r1; r2 := map r1; halt */
+    let words: [u32; 4] = [0xD3FFFFFF, 0x40000049, 0x80000011, 0x70000000];
+    let path = scratch("memory").join("huge.um");
+    fs::write(&path, words.map(u32::to_be_bytes).concat()).unwrap();
+    let output = um(&[path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(20));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("268435456"));
+}
+
+#[test]
+fn test_usage_exit_code() {
+    assert_eq!(um(&[]).status.code(), Some(1));
+    assert_eq!(um(&["tests/missing.um"]).status.code(), Some(1));
+}
+
+#[test]
+fn test_ums_pipes_stdin_to_stdout() {
+    let source = fs::read("tests/cat-new.ums").unwrap();
+    let output = ums(&["-"], &source);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, fs::read("tests/cat.um").unwrap());
+}
+
+#[test]
+fn test_ums_output_paths() {
+    let dir = scratch("ums");
+    let source = dir.join("a.b.ums");
+    fs::copy("tests/cat-new.ums", &source).unwrap();
+    let source = source.to_str().unwrap();
+
+    /* only the last extension is replaced */
+    assert_eq!(ums(&[source], b"").status.code(), Some(0));
+    assert_eq!(
+        fs::read(dir.join("a.b.um")).unwrap(),
+        fs::read("tests/cat.um").unwrap()
+    );
+
+    let out = dir.join("out");
+    fs::create_dir_all(&out).unwrap();
+    assert_eq!(
+        ums(&["-d", out.to_str().unwrap(), source], b"")
+            .status
+            .code(),
+        Some(0)
+    );
+    assert!(out.join("a.b.um").exists());
+
+    let named = dir.join("named.bin");
+    assert_eq!(
+        ums(&["-o", named.to_str().unwrap(), source], b"")
+            .status
+            .code(),
+        Some(0)
+    );
+    assert!(named.exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_ums_check() {
+    let output = ums(&["--check", "-"], b"r1 := 1\nhalt\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+
+    let output = ums(&["--check", "-"], b"r1 := bogus\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("<stdin>:1"));
+}
+
+#[test]
+fn test_ums_entry_point() {
+    let source = b".entry start\nhalt\nstart: r1 := 72\nout r1\nhalt\n";
+    let output = ums(&["-"], source);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs a container"));
+
+    let dir = scratch("entry");
+    let image = dir.join("entry.um");
+    let output = ums(&["-c", "-o", image.to_str().unwrap(), "-"], source);
+    assert_eq!(output.status.code(), Some(0));
+    let output = um(&[image.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"H");
+}
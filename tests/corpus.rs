@@ -0,0 +1,37 @@
+use um::um::{HaltReason, UM};
+use um::umio::MemoryIo;
+
+/*
+ * Runs the sample programs end to end. `cargo test --features unchecked`
+ * runs the same corpus without bounds checks.
+ */
+fn run(path: &str, input: &[u8]) -> Vec<u8> {
+    let mut machine = UM::with_io(MemoryIo::new(input));
+    machine.init_program(path).unwrap();
+    assert_eq!(machine.run(), HaltReason::Halted);
+    machine.io.output
+}
+
+#[test]
+fn test_corpus_hello() {
+    assert_eq!(run("tests/hello.um", b""), b"Hello, world.\n");
+}
+
+#[test]
+fn test_corpus_cat() {
+    assert_eq!(run("tests/cat.um", b"abc\n"), b"abc\n");
+}
+
+#[test]
+fn test_corpus_midmark() {
+    let output = run("tests/midmark.um", b"");
+    assert!(output.ends_with(b"Benchmark complete.\n"));
+}
+
+#[test]
+fn test_corpus_runs_off_end() {
+    /* add_two_numbers has no HALT, which the spec treats as a failure */
+    let mut machine = UM::with_io(MemoryIo::default());
+    machine.init_program("tests/add_two_numbers.um").unwrap();
+    assert_eq!(machine.run(), HaltReason::PcOutOfBounds { pc: 1 });
+}
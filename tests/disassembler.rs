@@ -0,0 +1,36 @@
+use std::fs;
+use um::assembler::UMAssembler;
+use um::disassembler::disassemble;
+use um::um::UmWord;
+
+fn words(path: &str) -> Vec<UmWord> {
+    fs::read(path)
+        .unwrap()
+        .chunks(4)
+        .map(|b| UmWord::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn assemble(source: &str) -> Vec<UmWord> {
+    UMAssembler {}
+        .assemble("test.ums", source.as_bytes())
+        .unwrap()
+}
+
+/* ums(umdis(x)) == x for every word of every program in tests/ */
+#[test]
+fn test_round_trip_programs() {
+    let mut checked = 0;
+    for entry in fs::read_dir("tests").unwrap() {
+        let path = entry.unwrap().path();
+        let extension = path.extension().and_then(|e| e.to_str());
+        if !matches!(extension, Some("um" | "umz")) {
+            continue;
+        }
+        let program = words(path.to_str().unwrap());
+        let source: String = program.iter().map(|&w| disassemble(w) + "\n").collect();
+        assert_eq!(assemble(&source), program, "{}", path.display());
+        checked += 1;
+    }
+    assert!(checked >= 6);
+}
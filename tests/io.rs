@@ -0,0 +1,26 @@
+use um::um::{HaltReason, UM};
+use um::umio::{MemoryIo, ScriptedIo};
+
+#[test]
+fn test_cat_echoes_input() {
+    let mut machine = UM::with_io(MemoryIo::new(b"Hello, cat!\n"));
+    machine.init_program("tests/cat.um").unwrap();
+    assert_eq!(machine.run(), HaltReason::Halted);
+    assert_eq!(machine.io.output, b"Hello, cat!\n");
+}
+
+#[test]
+fn test_hello_output() {
+    let mut machine = UM::with_io(MemoryIo::default());
+    machine.init_program("tests/hello.um").unwrap();
+    assert_eq!(machine.run(), HaltReason::Halted);
+    assert_eq!(machine.io.output, b"Hello, world.\n");
+}
+
+#[test]
+fn test_cat_scripted_transcript() {
+    let mut machine = UM::with_io(ScriptedIo::new(["one", "two"]));
+    machine.init_program("tests/cat.um").unwrap();
+    assert_eq!(machine.run(), HaltReason::Halted);
+    assert_eq!(machine.io.transcript, b"one\none\ntwo\ntwo\n");
+}
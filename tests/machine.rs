@@ -0,0 +1,84 @@
+use um::{HaltReason, LoadError, MachineError, MemoryIo, StepResult, UmBuilder};
+
+/* only the crate root is used here, as a library user would */
+
+#[test]
+fn test_builder_runs_file() {
+    let mut machine = UmBuilder::new()
+        .program_file("tests/hello.um")
+        .io(MemoryIo::default())
+        .build()
+        .unwrap();
+    assert_eq!(machine.run().unwrap(), HaltReason::Halted);
+    assert_eq!(machine.io().output, b"Hello, world.\n");
+}
+
+#[test]
+fn test_builder_fuel() {
+    /* r1 := 5; halt */
+    let mut machine = UmBuilder::new()
+        .program_words(vec![0xD2000005, 0x70000000])
+        .io(MemoryIo::default())
+        .fuel(1)
+        .build()
+        .unwrap();
+    assert_eq!(machine.step(), StepResult::Running);
+    assert_eq!(machine.registers()[1], 5);
+    assert_eq!(machine.read_word(0, 1), Some(0x70000000));
+    assert_eq!(machine.read_word(0, 2), None);
+    assert!(matches!(machine.run(), Err(MachineError::OutOfFuel)));
+    assert_eq!(machine.pc(), 1);
+}
+
+#[test]
+fn test_machine_stays_stopped() {
+    /* halt; r1 := 5; halt */
+    let mut machine = UmBuilder::new()
+        .program_words(vec![0x70000000, 0xD2000005, 0x70000000])
+        .io(MemoryIo::default())
+        .build()
+        .unwrap();
+    assert_eq!(machine.run().unwrap(), HaltReason::Halted);
+    assert_eq!(machine.run().unwrap(), HaltReason::Halted);
+    assert_eq!(machine.step(), StepResult::Halted(HaltReason::Halted));
+    assert_eq!(machine.registers()[1], 0);
+    assert_eq!(machine.pc(), 1);
+
+    /* the same with a budget, which a stopped machine no longer spends */
+    let mut machine = UmBuilder::new()
+        .program_words(vec![0xE0000000, 0x70000000])
+        .io(MemoryIo::default())
+        .fuel(5)
+        .build()
+        .unwrap();
+    assert!(matches!(
+        machine.step(),
+        StepResult::Halted(HaltReason::Fault(_))
+    ));
+    assert!(matches!(machine.run(), Err(MachineError::Fault(_))));
+    assert!(matches!(
+        machine.step(),
+        StepResult::Halted(HaltReason::Fault(_))
+    ));
+    assert_eq!(machine.fuel(), Some(4));
+    assert_eq!(machine.pc(), 1);
+}
+
+#[test]
+fn test_builder_errors() {
+    assert!(matches!(
+        UmBuilder::new().build(),
+        Err(MachineError::NoProgram)
+    ));
+    assert!(matches!(
+        UmBuilder::new().program_bytes(vec![0x70, 0]).build(),
+        Err(MachineError::Load(LoadError::TrailingBytes { len: 2 }))
+    ));
+
+    let mut machine = UmBuilder::new()
+        .program_words(vec![0xE0000000])
+        .io(MemoryIo::default())
+        .build()
+        .unwrap();
+    assert!(matches!(machine.run(), Err(MachineError::Fault(_))));
+}